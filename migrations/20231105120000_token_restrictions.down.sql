@@ -0,0 +1,3 @@
+ALTER TABLE token DROP COLUMN allowed_types;
+ALTER TABLE token DROP COLUMN max_files;
+ALTER TABLE token DROP COLUMN max_file_size_mib;
@@ -0,0 +1,5 @@
+-- comma separated list of mime types (image/png), mime prefixes (image/*)
+-- or file extensions (.pdf) accepted for this token. NULL means anything goes.
+ALTER TABLE token ADD COLUMN allowed_types TEXT;
+ALTER TABLE token ADD COLUMN max_files INTEGER;
+ALTER TABLE token ADD COLUMN max_file_size_mib INTEGER;
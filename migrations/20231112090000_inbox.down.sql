@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS upload_batch;
+ALTER TABLE token DROP COLUMN multi_use;
@@ -0,0 +1,16 @@
+-- a multi use token (inbox) accepts several uploads until valid_until
+ALTER TABLE token ADD COLUMN multi_use INTEGER NOT NULL DEFAULT 0;
+
+-- one row per successful upload for a token. For a single use token there is
+-- at most one, for a multi use token, one per submission.
+CREATE TABLE IF NOT EXISTS upload_batch
+( id INTEGER PRIMARY KEY NOT NULL
+, token_id INTEGER NOT NULL
+-- the files of the batch are the ones with the same token_id and attempt_counter
+, attempt_counter INTEGER NOT NULL
+, uploader_name TEXT
+, message TEXT
+, created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now', 'utc')) -- datetime
+, FOREIGN KEY(token_id) REFERENCES token(id)
+, UNIQUE(token_id, attempt_counter)
+) STRICT;
@@ -0,0 +1 @@
+ALTER TABLE upload_batch DROP COLUMN uploader_email;
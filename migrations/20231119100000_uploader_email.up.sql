@@ -0,0 +1 @@
+ALTER TABLE upload_batch ADD COLUMN uploader_email TEXT;
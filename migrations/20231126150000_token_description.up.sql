@@ -0,0 +1,3 @@
+ALTER TABLE token ADD COLUMN title TEXT;
+-- markdown, displayed to the uploaders and the people downloading the files
+ALTER TABLE token ADD COLUMN description TEXT;
@@ -0,0 +1,14 @@
+-- blobs generated from an uploaded file, like thumbnails. They are stored
+-- with the same backend as their parent and deleted alongside it.
+CREATE TABLE IF NOT EXISTS derived_file
+( id INTEGER PRIMARY KEY NOT NULL
+, file_id INTEGER NOT NULL
+-- what kind of derivation, like thumb or preview
+, kind TEXT NOT NULL
+, mime_type TEXT
+, backend_type TEXT NOT NULL
+, backend_data TEXT NOT NULL -- JSON
+, created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now', 'utc')) -- datetime
+, FOREIGN KEY(file_id) REFERENCES file(id)
+, UNIQUE(file_id, kind)
+) STRICT;
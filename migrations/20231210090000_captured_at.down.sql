@@ -0,0 +1 @@
+ALTER TABLE file_metadata DROP COLUMN captured_at;
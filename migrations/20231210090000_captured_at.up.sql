@@ -0,0 +1 @@
+ALTER TABLE file_metadata ADD COLUMN captured_at TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE token DROP COLUMN keep_original;
+ALTER TABLE token DROP COLUMN strip_metadata;
@@ -0,0 +1,4 @@
+-- remove the location and device data from uploaded pictures
+ALTER TABLE token ADD COLUMN strip_metadata INTEGER NOT NULL DEFAULT 0;
+-- when stripping, also keep the unmodified upload, only visible to the admins
+ALTER TABLE token ADD COLUMN keep_original INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE file_metadata DROP COLUMN etag;
+ALTER TABLE file_metadata DROP COLUMN source_url;
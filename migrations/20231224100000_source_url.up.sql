@@ -0,0 +1,3 @@
+-- for the files mirrored from another server
+ALTER TABLE file_metadata ADD COLUMN source_url TEXT;
+ALTER TABLE file_metadata ADD COLUMN etag TEXT;
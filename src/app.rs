@@ -1,21 +1,22 @@
 use axum::extract::{DefaultBodyLimit, Path};
-use axum::http::StatusCode;
 use axum::{routing, Router};
-use tower::ServiceBuilder;
-use tower_http::services::ServeDir;
 use tower_http::trace::TraceLayer;
 
 use crate::handlers;
+use crate::keys;
+use crate::logging;
+use crate::metrics;
 use crate::state::AppState;
 
 pub fn build(state: AppState) -> Router<()> {
-    let service = ServiceBuilder::new().layer(TraceLayer::new_for_http());
-    Router::new()
-        .layer(service)
+    let metrics_on_main = state.metrics.enabled && state.metrics.bind_address.is_none();
+    let router = Router::new()
         .route(
             "/",
             routing::get(|| async { axum::response::Redirect::temporary("/gen") }),
         )
+        .route("/healthz", routing::get(handlers::health::get_healthz))
+        .route("/readyz", routing::get(handlers::health::get_readyz))
         // TODO: instead of an extractor for the admin check, see if that can be done
         // using a middleware for this route
         .route(
@@ -40,17 +41,71 @@ pub fn build(state: AppState) -> Router<()> {
                     }),
                 )
                 .route("/f/:path/:file_id", routing::get(handlers::file::get_file))
+                .route(
+                    "/f/:path/:file_id/thumb",
+                    routing::get(handlers::file::get_thumbnail),
+                )
+                .route(
+                    "/f/:path/:file_id/preview",
+                    routing::get(handlers::file::get_preview),
+                )
+                .route(
+                    "/f/:path/:file_id/original",
+                    routing::get(handlers::file::get_original),
+                )
+                .route(
+                    "/f/:path/:file_id/view",
+                    routing::get(handlers::file::get_text_preview),
+                )
+                // a static segment so that no file name can collide with the other routes
+                .route(
+                    "/f/:path/raw/:file_name",
+                    routing::put(handlers::upload::put_raw_file)
+                        .post(handlers::upload::put_raw_file),
+                )
+                .route(
+                    "/f/:path/paste",
+                    routing::post(handlers::upload::post_paste),
+                )
+                .route(
+                    "/f/:path/fetch",
+                    routing::post(handlers::upload::post_fetch),
+                )
+                .route(
+                    "/f/:path/batches",
+                    routing::get(handlers::upload::get_batches),
+                )
+                .route(
+                    "/f/:path/batches/:file_id",
+                    routing::get(handlers::file::get_batch_file),
+                )
                 .layer(DefaultBodyLimit::max(usize::MAX))
                 .with_state(state.clone()),
         )
-        .nest_service(
-            "/static",
-            routing::get_service(ServeDir::new("static")).handle_error(
-                |err: std::io::Error| async move {
-                    tracing::error!("Error serving static file: {err:?}");
-                    (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:?}"))
-                },
-            ),
+        .route(
+            "/static/*path",
+            routing::get(handlers::assets::get_static_file),
         )
+        .layer(axum::middleware::from_fn(metrics::track_requests))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            keys::accept_previous_keys,
+        ))
+        // the last layers run first: the request id must be set before the span
+        .layer(TraceLayer::new_for_http().make_span_with(logging::request_span))
+        .layer(axum::middleware::from_fn(logging::set_request_id))
+        .with_state(state.clone());
+
+    if metrics_on_main {
+        router.merge(build_metrics(state))
+    } else {
+        router
+    }
+}
+
+/// only /metrics, for when it is served on its own address
+pub fn build_metrics(state: AppState) -> Router<()> {
+    Router::new()
+        .route("/metrics", routing::get(handlers::metrics::get_metrics))
         .with_state(state)
 }
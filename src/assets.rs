@@ -0,0 +1,248 @@
+//! Templates and static files embedded in the binary. The `templates/` and `static/`
+//! directories of `server.assets_dir` can replace or add files.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use parking_lot::RwLock;
+use tera::{Tera, Value};
+
+use crate::filters::{humanize_size, markdown};
+
+/// (name, content) of a file of the repository, under `dir`
+macro_rules! embed {
+    ($include:ident, $dir:literal, $name:literal) => {
+        ($name, $include!(concat!("../", $dir, "/", $name)))
+    };
+}
+
+/// new templates must be added here to be part of the binary
+const TEMPLATES: &[(&str, &str)] = &[
+    embed!(include_str, "templates", "base.html"),
+    embed!(include_str, "templates", "get_batches.html"),
+    embed!(include_str, "templates", "get_files.html"),
+    embed!(include_str, "templates", "get_gen_token.html"),
+    embed!(include_str, "templates", "macros.html"),
+    embed!(include_str, "templates", "no_link_found.html"),
+    embed!(include_str, "templates", "text_preview.html"),
+    embed!(include_str, "templates", "upload_form.html"),
+];
+
+/// same for the static files
+const STATIC_FILES: &[(&str, &[u8])] = &[
+    embed!(include_bytes, "static", "gallery.js"),
+    embed!(include_bytes, "static", "styles.css"),
+    embed!(include_bytes, "static", "upload.js"),
+];
+
+#[derive(Debug)]
+pub(crate) struct StaticFile {
+    pub(crate) content: Bytes,
+    pub(crate) mime_type: String,
+    /// of the content, to change the url whenever the file changes
+    pub(crate) hash: String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct StaticAssets {
+    files: HashMap<String, StaticFile>,
+}
+
+impl StaticAssets {
+    pub(crate) fn load(override_dir: Option<&Path>) -> std::io::Result<Self> {
+        let mut files: HashMap<String, StaticFile> = STATIC_FILES
+            .iter()
+            .map(|(name, content)| (name.to_string(), static_file(name, Bytes::from(*content))))
+            .collect();
+
+        if let Some(dir) = override_dir {
+            let dir = dir.join("static");
+            for (name, path) in list_files(&dir)? {
+                let content = Bytes::from(std::fs::read(&path)?);
+                files.insert(name.clone(), static_file(&name, content));
+            }
+        }
+
+        Ok(Self { files })
+    }
+
+    /// `/static/styles.1a2b3c4d.css` for `styles.css`
+    pub(crate) fn url(&self, name: &str) -> Option<String> {
+        let file = self.files.get(name)?;
+        let hashed = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() && !stem.ends_with('/') => {
+                format!("{stem}.{}.{ext}", file.hash)
+            }
+            _ => format!("{name}.{}", file.hash),
+        };
+        Some(format!("/static/{hashed}"))
+    }
+
+    /// The file for a path under /static, either its plain name or its hashed url.
+    /// The boolean is true when the hash is the one of the current content, so
+    /// that the response can be cached forever.
+    pub(crate) fn get(&self, path: &str) -> Option<(&StaticFile, bool)> {
+        if let Some(file) = self.files.get(path) {
+            return Some((file, false));
+        }
+
+        let (rest, last) = path.rsplit_once('.')?;
+        // styles.<hash>.css, or <name>.<hash> without extension
+        let candidates = [
+            rest.rsplit_once('.')
+                .map(|(stem, hash)| (format!("{stem}.{last}"), hash)),
+            Some((rest.to_string(), last)),
+        ];
+        candidates.into_iter().flatten().find_map(|(name, hash)| {
+            let file = self.files.get(&name)?;
+            Some((file, file.hash == hash))
+        })
+    }
+}
+
+fn static_file(name: &str, content: Bytes) -> StaticFile {
+    let mime_type = mime_guess::from_path(name)
+        .first_or_octet_stream()
+        .to_string();
+    let hash = format!("{:08x}", crc32fast::hash(&content));
+    StaticFile {
+        content,
+        mime_type,
+        hash,
+    }
+}
+
+/// The embedded templates, then the ones from the override directory, with the
+/// filters and the `static_url` function.
+pub(crate) fn load_templates(
+    override_dir: Option<&Path>,
+    assets: Arc<RwLock<StaticAssets>>,
+) -> tera::Result<Tera> {
+    let mut templates: HashMap<String, String> = TEMPLATES
+        .iter()
+        .map(|(name, content)| (name.to_string(), content.to_string()))
+        .collect();
+
+    if let Some(dir) = override_dir {
+        let dir = dir.join("templates");
+        let files = list_files(&dir).map_err(|err| {
+            tera::Error::chain(format!("Cannot list templates in {}", dir.display()), err)
+        })?;
+        for (name, path) in files {
+            if !name.ends_with(".html") {
+                continue;
+            }
+            let content = std::fs::read_to_string(&path).map_err(|err| {
+                tera::Error::chain(format!("Cannot read template {}", path.display()), err)
+            })?;
+            templates.insert(name, content);
+        }
+    }
+
+    let mut tera = Tera::default();
+    tera.add_raw_templates(templates)?;
+    tera.register_filter("humanize_size", humanize_size);
+    tera.register_filter("markdown", markdown);
+    tera.register_function("static_url", StaticUrl(assets));
+    Ok(tera)
+}
+
+/// `{{ static_url(name="styles.css") }}` in the templates, for the hashed url
+struct StaticUrl(Arc<RwLock<StaticAssets>>);
+
+impl tera::Function for StaticUrl {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let name = args
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| tera::Error::msg("static_url expects a `name` argument"))?;
+        self.0
+            .read()
+            .url(name)
+            .map(Value::from)
+            .ok_or_else(|| tera::Error::msg(format!("Unknown static file {name}")))
+    }
+
+    // the names are only from the embedded files or the override directory
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// All the files under `dir`, with their path relative to it, `/` separated.
+/// A missing directory has no files.
+fn list_files(dir: &Path) -> std::io::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    if !dir.is_dir() {
+        return Ok(files);
+    }
+    let mut to_visit = vec![dir.to_path_buf()];
+    while let Some(current) = to_visit.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                to_visit.push(path);
+                continue;
+            }
+            let Ok(relative) = path.strip_prefix(dir) else {
+                continue;
+            };
+            let name = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((name, path));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates() -> Tera {
+        let assets = StaticAssets::load(None).unwrap();
+        load_templates(None, Arc::new(RwLock::new(assets))).unwrap()
+    }
+
+    #[test]
+    fn description_is_html() {
+        let tera = templates();
+        let mut ctx = tera::Context::new();
+        ctx.insert(
+            "description",
+            "Some **holiday** pictures <script>alert(1)</script>",
+        );
+        // what the pages need besides the description
+        for key in ["valid_for", "tok_path", "base_url"] {
+            ctx.insert(key, "");
+        }
+        for key in [
+            "max_name_len",
+            "max_email_len",
+            "max_message_len",
+            "max_paste_name_len",
+        ] {
+            ctx.insert(key, &100);
+        }
+        for key in ["paste_languages", "files", "images"] {
+            ctx.insert(key, &Vec::<String>::new());
+        }
+
+        for name in ["upload_form.html", "get_files.html"] {
+            let html = tera.render(name, &ctx).unwrap();
+            assert!(
+                html.contains("<p>Some <strong>holiday</strong> pictures"),
+                "{name}"
+            );
+            assert!(!html.contains("&lt;p&gt;"), "{name}");
+            assert!(!html.contains("<script>alert"), "{name}");
+        }
+    }
+}
@@ -2,7 +2,7 @@
 use anyhow::Context;
 use clap::Parser;
 use sqlx::{sqlite::SqlitePoolOptions, Executor};
-use vrac::state::AppState;
+use vrac::{config::Config, state::AppState};
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -22,14 +22,12 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::parse();
     tracing::debug!("running with args {args:?}");
-    let state = AppState::new(
-        "templates/**/*.html",
-        &args.sqlite_path,
-        &args.storage_path,
-        "useless".to_string(),
-    )
-    .await
-    .context("cannot construct app state")?;
+    let mut config = Config::default();
+    config.storage.sqlite_path = args.sqlite_path.clone().into();
+    config.storage.local_path = args.storage_path.clone().into();
+    let state = AppState::new(&config)
+        .await
+        .context("cannot construct app state")?;
 
     state.db.migrate().await?;
 
@@ -6,12 +6,24 @@ use std::str::FromStr;
 use anyhow::{anyhow, Context};
 use axum::Router;
 use base64::Engine;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use hyper::server::{accept::Accept, conn::AddrIncoming};
 use hyper::{Body, Request};
 use hyper_tls::HttpsConnector;
-use mpart_async::client::MultipartRequest;
-use vrac::handlers::gen::{GenTokenForm, StorageBackendType};
-use vrac::{app::build, state::AppState};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+use tokio_util::sync::CancellationToken;
+use vrac::handlers::gen::{GenTokenForm, PathKind, StorageBackendType};
+use vrac::{
+    app::{build, build_metrics},
+    config::{Config, LogFormat, UnixSocketConfig},
+    listener::{TlsCertificates, TlsIncoming, UnixIncoming},
+    logging,
+    state::AppState,
+};
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -23,130 +35,430 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Command {
     Serve {
-        #[arg(long, default_value = "./test.sqlite")]
-        sqlite_path: String,
-
-        #[arg(long, default_value = "/tmp/vrac/")]
-        storage_path: String,
-
-        #[arg(long, default_value_t = 8000)]
-        port: u16,
-
-        #[arg(long, default_value = "127.0.0.1")]
-        bind_address: String,
-
-        /// used to construct absolute urls
-        #[arg(long, default_value = "https://vrac.geekingfrog.com")]
-        base_url: String
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// inspect the configuration of the server
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
     },
     Upload {
         path: PathBuf,
 
-        #[arg(long, default_value = "https://vrac.geekingfrog.com")]
-        base_url: String,
+        #[command(flatten)]
+        link: LinkArgs,
+    },
+    /// mirror a remote file, the server downloads it directly
+    Fetch {
+        url: String,
 
+        /// name of the file, instead of the one given by the remote server
         #[arg(long)]
-        name: Option<String>,
+        file_name: Option<String>,
 
-        #[arg(long, default_value_t = 48)]
-        expires_hours: i64,
+        #[command(flatten)]
+        link: LinkArgs,
+    },
+}
 
-        #[arg(short, long, default_value_t = false)]
-        no_expires: bool,
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// validate the configuration and print it, with the overrides applied
+    Check {
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// generate a new key to sign the cookies, the previous ones are still accepted
+    /// for a while. Takes effect when the server restarts.
+    RotateKey {
+        #[command(flatten)]
+        config: ConfigArgs,
     },
 }
 
+/// The settings of the server come from the config file, then the VRAC_* environment
+/// variables, and these flags have the last word.
+#[derive(Args, Debug)]
+struct ConfigArgs {
+    /// toml config file
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    #[arg(long)]
+    sqlite_path: Option<PathBuf>,
+
+    #[arg(long)]
+    storage_path: Option<PathBuf>,
+
+    #[arg(long)]
+    port: Option<u16>,
+
+    #[arg(long)]
+    bind_address: Option<String>,
+
+    /// used to construct absolute urls
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// remove the location and device information from pictures by default
+    /// for new links
+    #[arg(long, default_value_t = false)]
+    strip_metadata: bool,
+
+    /// reload the templates and static files when they change
+    #[arg(long, default_value_t = false)]
+    dev: bool,
+}
+
+impl ConfigArgs {
+    fn load(self) -> anyhow::Result<Config> {
+        let mut config = Config::load(self.config.as_deref())?;
+        if let Some(sqlite_path) = self.sqlite_path {
+            config.storage.sqlite_path = sqlite_path;
+        }
+        if let Some(storage_path) = self.storage_path {
+            config.storage.local_path = storage_path;
+        }
+        if let Some(port) = self.port {
+            config.server.port = port;
+        }
+        if let Some(bind_address) = self.bind_address {
+            config.server.bind_address = bind_address;
+        }
+        if let Some(base_url) = self.base_url {
+            config.server.base_url = base_url;
+        }
+        if self.strip_metadata {
+            config.limits.strip_metadata_default = true;
+        }
+        if self.dev {
+            config.server.dev = true;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// how to create the link for the file
+#[derive(Args, Debug)]
+struct LinkArgs {
+    #[arg(long, default_value = "https://vrac.geekingfrog.com")]
+    base_url: String,
+
+    /// use this exact path for the link instead of generating a random one
+    #[arg(long)]
+    name: Option<String>,
+
+    /// prefix for the generated path
+    #[arg(long, conflicts_with = "name")]
+    prefix: Option<String>,
+
+    /// generate a path made of words instead of random characters
+    #[arg(long, default_value_t = false, conflicts_with = "name")]
+    words: bool,
+
+    #[arg(long, default_value_t = 48)]
+    expires_hours: i64,
+
+    #[arg(short, long, default_value_t = false)]
+    no_expires: bool,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-
     let cli = Cli::parse();
 
+    // the server logs in the format from its config, known once it's loaded
+    if !matches!(cli.command, Command::Serve { .. }) {
+        logging::init(LogFormat::Text, None)?;
+    }
+
     match cli.command {
-        Command::Serve {
-            sqlite_path,
-            storage_path,
-            port,
-            bind_address,
-            base_url,
-        } => serve(sqlite_path, storage_path, port, bind_address, base_url).await,
-        Command::Upload {
-            path,
-            base_url,
-            name,
-            expires_hours,
-            no_expires,
-        } => upload(path, base_url, name, expires_hours, no_expires).await,
-    }
-}
-
-async fn serve(
-    sqlite_path: String,
-    storage_path: String,
-    port: u16,
-    bind_address: String,
-    base_url: String,
-) -> anyhow::Result<()> {
-    tracing::info!("Local fs for storage at {}", storage_path);
-    tokio::fs::create_dir_all(&storage_path).await?;
+        Command::Serve { config } => {
+            let config = config.load()?;
+            logging::init(config.log.format, Some(&config.otlp))
+                .context("cannot set up the otlp exporter")?;
+            serve(config).await
+        }
+        Command::Config {
+            command: ConfigCommand::Check { config },
+        } => check_config(config.load()?),
+        Command::Config {
+            command: ConfigCommand::RotateKey { config },
+        } => rotate_key(config.load()?),
+        Command::Upload { path, link } => upload(path, link).await,
+        Command::Fetch {
+            url,
+            file_name,
+            link,
+        } => fetch(url, file_name, link).await,
+    }
+}
+
+async fn serve(config: Config) -> anyhow::Result<()> {
+    let storage_path = &config.storage.local_path;
+    let sqlite_path = &config.storage.sqlite_path;
+    tracing::info!("Local fs for storage at {}", storage_path.display());
+    tokio::fs::create_dir_all(storage_path).await?;
 
     tokio::fs::OpenOptions::new()
         .create(true)
         .truncate(false)
         .write(true)
-        .open(&sqlite_path)
+        .open(sqlite_path)
         .await?;
 
-    let state = AppState::new("templates/**/*.html", &sqlite_path, &storage_path, base_url)
+    let state = AppState::new(&config)
         .await
         .context("cannot construct app state")?;
     state.db.migrate().await?;
 
-    let addr = IpAddr::from_str(&bind_address)?;
-    let addr = SocketAddr::from((addr, port));
+    let addr = IpAddr::from_str(&config.server.bind_address)?;
+    let addr = SocketAddr::from((addr, config.server.port));
     let app = build(state.clone());
+    let shutdown = Shutdown {
+        token: shutdown_on_signal()?,
+        timeout: config.server.shutdown_timeout,
+    };
+
+    let tls = config
+        .server
+        .tls
+        .as_ref()
+        .map(TlsCertificates::load)
+        .transpose()?;
+
+    let res = tokio::try_join!(
+        webserver(addr, app.clone(), tls.clone(), &shutdown),
+        unix_webserver(config.server.unix_socket.as_ref(), app, &shutdown),
+        reload_tls_on_sighup(tls, &shutdown),
+        background_cleanup(
+            &state.db,
+            &state.storage_fs,
+            &state.garage,
+            config.cleanup.interval,
+            &shutdown
+        ),
+        watch_assets(state.clone(), &shutdown),
+        metrics_webserver(state.clone(), &shutdown)
+    );
+
+    state.db.close().await;
+    tracing::info!("Stopped");
+    // the export of the last spans waits on the runtime, so not from one of its threads
+    tokio::task::spawn_blocking(logging::shutdown).await?;
+    res?;
+    Ok(())
+}
 
-    tokio::try_join!(
-        webserver(addr, app),
-        background_cleanup(&state.db, &state.storage_fs, &state.garage)
-    )?;
+struct Shutdown {
+    /// cancelled when the server must stop
+    token: CancellationToken,
+    /// how long the requests in progress can go on after that
+    timeout: std::time::Duration,
+}
+
+/// cancelled on SIGTERM or ctrl-c
+fn shutdown_on_signal() -> anyhow::Result<CancellationToken> {
+    let token = CancellationToken::new();
+    let mut sigterm = signal(SignalKind::terminate()).context("cannot listen to SIGTERM")?;
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = sigterm.recv() => (),
+        }
+        tracing::info!("Shutting down, waiting for the requests in progress");
+        cancel.cancel();
+    });
+    Ok(token)
+}
 
+fn check_config(mut config: Config) -> anyhow::Result<()> {
+    if config.auth.signing_key.is_some() {
+        config.auth.signing_key = Some("<redacted>".to_string());
+    }
+    for key in config.auth.previous_signing_keys.iter_mut() {
+        *key = "<redacted>".to_string();
+    }
+    if config.metrics.token.is_some() {
+        config.metrics.token = Some("<redacted>".to_string());
+    }
+    // likely credentials for the collector
+    for value in config.otlp.headers.values_mut() {
+        *value = "<redacted>".to_string();
+    }
+    println!("{}", toml::to_string_pretty(&config)?);
+    eprintln!("The configuration is valid.");
+    Ok(())
+}
+
+fn rotate_key(config: Config) -> anyhow::Result<()> {
+    if config.auth.signing_key.is_some() {
+        return Err(anyhow!(
+            "The signing key is set in the config, move it to auth.previous_signing_keys \
+             and set a new one instead."
+        ));
+    }
+    let key_file = config.key_file();
+    vrac::keys::rotate_key_file(&key_file)?;
+    eprintln!(
+        "New signing key in {}, restart the server to use it.",
+        key_file.display()
+    );
+    Ok(())
+}
+
+async fn watch_assets(state: AppState, shutdown: &Shutdown) -> anyhow::Result<()> {
+    if state.dev {
+        tokio::select! {
+            res = vrac::dev::watch_assets(state) => res.context("cannot watch the assets")?,
+            _ = shutdown.token.cancelled() => (),
+        }
+    }
     Ok(())
 }
 
-async fn webserver(addr: SocketAddr, app: Router) -> anyhow::Result<()> {
-    tracing::info!("Listening on {}", addr);
-    axum::Server::bind(&addr)
+/// /metrics on its own address, when configured so
+async fn metrics_webserver(state: AppState, shutdown: &Shutdown) -> anyhow::Result<()> {
+    match state.metrics.bind_address.clone() {
+        Some(addr) if state.metrics.enabled => {
+            let addr = SocketAddr::from_str(&addr)?;
+            webserver(addr, build_metrics(state), None, shutdown).await
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn webserver(
+    addr: SocketAddr,
+    app: Router,
+    tls: Option<TlsCertificates>,
+    shutdown: &Shutdown,
+) -> anyhow::Result<()> {
+    match tls {
+        Some(certificates) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("cannot listen on {addr}"))?;
+            tracing::info!("Listening on {} with TLS", addr);
+            let incoming = TlsIncoming::new(listener, certificates);
+            serve_connections(incoming, &addr.to_string(), app, shutdown).await
+        }
+        None => {
+            tracing::info!("Listening on {}", addr);
+            let incoming = AddrIncoming::bind(&addr)?;
+            serve_connections(incoming, &addr.to_string(), app, shutdown).await
+        }
+    }
+}
+
+async fn unix_webserver(
+    socket: Option<&UnixSocketConfig>,
+    app: Router,
+    shutdown: &Shutdown,
+) -> anyhow::Result<()> {
+    let Some(socket) = socket else {
+        return Ok(());
+    };
+    let incoming = UnixIncoming::bind(socket)?;
+    let name = socket.path.display().to_string();
+    tracing::info!("Listening on {}", name);
+    let res = serve_connections(incoming, &name, app, shutdown).await;
+    if let Err(err) = std::fs::remove_file(&socket.path) {
+        tracing::warn!("Cannot remove the socket {}: {err}", name);
+    }
+    res
+}
+
+/// Replace the certificates on SIGHUP, like after a renewal.
+async fn reload_tls_on_sighup(
+    tls: Option<TlsCertificates>,
+    shutdown: &Shutdown,
+) -> anyhow::Result<()> {
+    let Some(certificates) = tls else {
+        return Ok(());
+    };
+    let mut sighup = signal(SignalKind::hangup()).context("cannot listen to SIGHUP")?;
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => (),
+            _ = shutdown.token.cancelled() => return Ok(()),
+        }
+        match certificates.reload() {
+            Ok(()) => tracing::info!("TLS certificates reloaded"),
+            Err(err) => {
+                tracing::error!("Cannot reload the TLS certificates, keeping the old ones: {err}")
+            }
+        }
+    }
+}
+
+/// Stops accepting connections on shutdown, and waits for the requests in
+/// progress until the shutdown timeout.
+async fn serve_connections<I>(
+    incoming: I,
+    name: &str,
+    app: Router,
+    shutdown: &Shutdown,
+) -> anyhow::Result<()>
+where
+    I: Accept,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let server = axum::Server::builder(incoming)
         .serve(app.into_make_service())
-        .await?;
+        .with_graceful_shutdown(shutdown.token.cancelled());
+    let deadline = async {
+        shutdown.token.cancelled().await;
+        tokio::time::sleep(shutdown.timeout).await;
+    };
+
+    tokio::select! {
+        res = server => res?,
+        _ = deadline => tracing::warn!(
+            "Requests still in progress on {name} after {}, stopping anyway",
+            humantime::format_duration(shutdown.timeout)
+        ),
+    }
     Ok(())
 }
 
+/// A cleanup in progress is completed before stopping, to not leave files
+/// deleted from the storage but not from the db.
 async fn background_cleanup(
     db: &vrac::db::DBService,
     storage_fs: &vrac::upload::LocalFsUploader,
     garage: &vrac::upload::GarageUploader,
+    interval: std::time::Duration,
+    shutdown: &Shutdown,
 ) -> anyhow::Result<()> {
     loop {
         vrac::cleanup::cleanup(&db, &storage_fs, &garage)
             .await
             .context("cleanup task failed")?;
-        tokio::time::sleep(std::time::Duration::from_secs(60 * 5)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => (),
+            _ = shutdown.token.cancelled() => return Ok(()),
+        }
     }
 }
 
-async fn upload(
-    path: PathBuf,
-    base_url: String,
-    name: Option<String>,
-    expires_hours: i64,
-    no_expires: bool,
-) -> anyhow::Result<()> {
-    let base_url = url::Url::parse(&base_url)
-        .with_context(|| format!("Invalid base url to bind server {base_url}"))?;
+type Client = hyper::Client<HttpsConnector<hyper::client::HttpConnector>>;
 
-    let https = HttpsConnector::new();
-    let client = hyper::Client::builder().build::<_, hyper::Body>(https);
+/// Create a new link with the admin credentials from the environment.
+/// Returns the url of the link and the authorization header to use it.
+async fn create_link(client: &Client, link: LinkArgs) -> anyhow::Result<(url::Url, String)> {
+    let base_url = url::Url::parse(&link.base_url)
+        .with_context(|| format!("Invalid base url to bind server {}", link.base_url))?;
+
+    let (path_kind, link_path) = match (link.name, link.words) {
+        (Some(name), _) => (PathKind::Manual, name),
+        (None, false) => (PathKind::Random, link.prefix.unwrap_or_default()),
+        (None, true) => (PathKind::Words, link.prefix.unwrap_or_default()),
+    };
 
     let mut gen_url = base_url.clone();
     gen_url.set_path("/gen");
@@ -156,23 +468,29 @@ async fn upload(
 
     let raw_auth = format!("{}:{}", username, password);
     let encoded_auth = base64::engine::general_purpose::STANDARD_NO_PAD.encode(raw_auth.as_bytes());
+    let auth = format!("Basic {}", encoded_auth);
 
-    let filename = name
-        .or_else(|| path.file_name().map(|s| s.to_string_lossy().into_owned()))
-        .ok_or(anyhow!("Cannot get filename"))?;
-
-    let content_expires_after_hours = if no_expires {
+    let content_expires_after_hours = if link.no_expires {
         None
     } else {
-        Some(expires_hours)
+        Some(link.expires_hours)
     };
 
     let form = GenTokenForm {
-        path: filename,
+        path: link_path,
+        path_kind,
         max_size_mib: None,
         content_expires_after_hours,
         token_valid_for_hour: 1,
         storage_backend: StorageBackendType::LocalFS,
+        allowed_types: None,
+        max_files: None,
+        max_file_size_mib: None,
+        multi_use: false,
+        title: None,
+        description: None,
+        strip_metadata: false,
+        keep_original: false,
     };
 
     tracing::debug!("gentokenform is: {:?}", serde_urlencoded::to_string(&form));
@@ -181,10 +499,7 @@ async fn upload(
             hyper::header::CONTENT_TYPE,
             "application/x-www-form-urlencoded",
         )
-        .header(
-            hyper::header::AUTHORIZATION,
-            format!("Basic {}", encoded_auth),
-        )
+        .header(hyper::header::AUTHORIZATION, &auth)
         .body(serde_urlencoded::to_string(&form)?.into())?;
 
     let response = client.request(request).await?;
@@ -201,21 +516,36 @@ async fn upload(
 
     let mut upload_url = base_url.clone();
     upload_url.set_path(location.to_str()?);
+    Ok((upload_url, auth))
+}
 
-    let mut mparts = MultipartRequest::default();
-    mparts.add_file("file_1", path);
-
-    let request = Request::post(hyper::Uri::from_str(upload_url.as_str()).unwrap())
-        .header(
-            hyper::header::CONTENT_TYPE,
-            format!("multipart/form-data; boundary={}", mparts.get_boundary()),
-        )
-        .body(Body::wrap_stream(mparts))?;
+async fn upload(path: PathBuf, link: LinkArgs) -> anyhow::Result<()> {
+    let https = HttpsConnector::new();
+    let client = hyper::Client::builder().build::<_, hyper::Body>(https);
+    let (upload_url, _) = create_link(&client, link).await?;
+
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Invalid file name: {}", path.display()))?;
+    let mut file_url = upload_url.clone();
+    file_url
+        .path_segments_mut()
+        .map_err(|_| anyhow!("Invalid upload url {upload_url}"))?
+        .extend(["raw", file_name]);
+    let mime_type = mime_guess::from_path(&path).first_or_octet_stream();
+
+    let file = tokio::fs::File::open(&path)
+        .await
+        .with_context(|| format!("Cannot open {}", path.display()))?;
+    let request = Request::put(hyper::Uri::from_str(file_url.as_str()).unwrap())
+        .header(hyper::header::CONTENT_TYPE, mime_type.essence_str())
+        .body(Body::wrap_stream(tokio_util::io::ReaderStream::new(file)))?;
 
     let response = client.request(request).await?;
 
     let status = response.status();
-    if !status.is_redirection() {
+    if !status.is_success() {
         let body = hyper::body::to_bytes(response).await?;
         let strbody = String::from_utf8(body.to_vec())?;
         return Err(anyhow!("Couldn't upload files {}\n{}", status, strbody).into());
@@ -225,3 +555,37 @@ async fn upload(
     println!("{}", upload_url);
     Ok(())
 }
+
+async fn fetch(url: String, file_name: Option<String>, link: LinkArgs) -> anyhow::Result<()> {
+    let https = HttpsConnector::new();
+    let client = hyper::Client::builder().build::<_, hyper::Body>(https);
+    let (upload_url, auth) = create_link(&client, link).await?;
+
+    let mut fetch_url = upload_url.clone();
+    fetch_url
+        .path_segments_mut()
+        .map_err(|_| anyhow!("Invalid upload url {upload_url}"))?
+        .push("fetch");
+
+    let mut form = vec![("url", url)];
+    form.extend(file_name.map(|n| ("name", n)));
+    let request = Request::post(hyper::Uri::from_str(fetch_url.as_str()).unwrap())
+        .header(
+            hyper::header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded",
+        )
+        .header(hyper::header::AUTHORIZATION, auth)
+        .body(serde_urlencoded::to_string(&form)?.into())?;
+
+    // the server only responds once the whole file has been downloaded
+    let response = client.request(request).await?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response).await?;
+    let strbody = String::from_utf8(body.to_vec())?;
+    if !status.is_success() {
+        return Err(anyhow!("Couldn't fetch the file {}\n{}", status, strbody));
+    }
+
+    println!("{}", upload_url);
+    Ok(())
+}
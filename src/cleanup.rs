@@ -6,6 +6,7 @@ use time::OffsetDateTime;
 use crate::{
     db::{DBService, DbFile},
     error::{AppError, Result},
+    metrics::metrics,
     upload::{GarageUploader, LocalFsUploader, StorageBackend},
 };
 
@@ -14,62 +15,90 @@ pub async fn cleanup(
     storage: &LocalFsUploader,
     garage: &GarageUploader,
 ) -> Result<()> {
+    let metrics = metrics();
+    let timer = metrics.cleanup_duration.start_timer();
+    let res = delete_expired(db, storage, garage).await;
+    timer.observe_duration();
+    match res {
+        Ok(deleted) => {
+            metrics.cleanup_deleted_files.inc_by(deleted as u64);
+            Ok(())
+        }
+        Err(err) => {
+            metrics.cleanup_failures.inc();
+            Err(err)
+        }
+    }
+}
+
+/// returns the number of deleted files
+async fn delete_expired(
+    db: &DBService,
+    storage: &LocalFsUploader,
+    garage: &GarageUploader,
+) -> Result<usize> {
     let now = OffsetDateTime::now_utc();
     let files = db.get_files_to_delete(&now).await?;
 
     if files.is_empty() {
-        return Ok(());
+        return Ok(0);
     }
 
     future::try_join_all(
         files
             .iter()
-            .map(|f| async move { delete_file(storage, garage, f).await }),
+            .map(|f| async move { delete_file(db, storage, garage, f).await }),
     )
     .await?;
 
     let token_ids: BTreeSet<_> = files.iter().map(|f| f.token_id).collect();
     tracing::info!(
-        "deleted {} files associated with {} tokens",
-        files.len(),
-        token_ids.len()
+        files = files.len(),
+        tokens = token_ids.len(),
+        "Deleted expired files"
     );
 
     db.delete_files(files.iter().map(|f| f.id)).await?;
     let deleted_ids = db.delete_expired_tokens(&now).await?;
-    tracing::info!(
-        "deleted expired tokens with ids and paths: {:?}",
-        deleted_ids
-    );
+    tracing::info!(tokens = ?deleted_ids, "Deleted expired tokens");
 
-    Ok(())
+    Ok(files.len())
 }
 
 async fn delete_file(
+    db: &DBService,
     storage: &LocalFsUploader,
     garage: &GarageUploader,
     file: &DbFile,
 ) -> Result<()> {
     tracing::info!(
-        "Attempting to delete file {} (token {})",
-        file.id,
-        file.token_id
+        token_id = file.token_id,
+        file_id = file.id,
+        backend = %file.backend_type,
+        "Deleting file"
     );
-    let res = match file.backend_type.as_str() {
-        "local_fs" => {
-            storage.delete_blob(file.backend_data.clone()).await
-        }
-        "garage" => {
-            garage.delete_blob(file.backend_data.clone()).await
-        }
-        bt => {
-            tracing::error!("Unknown backend type {bt} for file {}", file.id);
-            Ok(())
+
+    // thumbnails and such first, the rows are removed along with the file
+    let mut res = Ok(());
+    for derived in db.get_derived_files(file.id).await? {
+        res = delete_blob(
+            storage,
+            garage,
+            &derived.backend_type,
+            &derived.backend_data,
+        )
+        .await;
+        if res.is_err() {
+            break;
         }
-    };
+    }
+    if res.is_ok() {
+        res = delete_blob(storage, garage, &file.backend_type, &file.backend_data).await;
+    }
+
     match res {
         Ok(_) => {
-            tracing::info!("Successfully deleted file with id {}", file.id);
+            tracing::info!(token_id = file.token_id, file_id = file.id, "Deleted file");
             Ok(())
         }
         Err(err) => Err(AppError::DeleteBlobError {
@@ -79,3 +108,19 @@ async fn delete_file(
         }),
     }
 }
+
+async fn delete_blob(
+    storage: &LocalFsUploader,
+    garage: &GarageUploader,
+    backend_type: &str,
+    backend_data: &str,
+) -> Result<()> {
+    match backend_type {
+        "local_fs" => storage.delete_blob(backend_data.to_string()).await,
+        "garage" => garage.delete_blob(backend_data.to_string()).await,
+        bt => {
+            tracing::error!(backend = bt, data = backend_data, "Unknown backend type");
+            Ok(())
+        }
+    }
+}
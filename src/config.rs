@@ -0,0 +1,583 @@
+//! Settings of the server: the defaults, then a toml file, then the `VRAC_*`
+//! environment variables like `VRAC_SERVER__PORT=8080` (sections and keys separated
+//! by two underscores), then the command line flags.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// prefix of the environment variables overriding the config file
+const ENV_PREFIX: &str = "VRAC_";
+const ENV_SEPARATOR: &str = "__";
+
+/// the cookie crate refuses shorter keys
+const MIN_SIGNING_KEY_LEN: usize = 64;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Cannot read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid config: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    #[error("Invalid environment variable {var}: {reason}")]
+    Env { var: String, reason: String },
+
+    #[error("Cannot read or write key file {path}: {source}")]
+    KeyFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Invalid value for {key}: {reason}")]
+    Invalid { key: &'static str, reason: String },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub cleanup: CleanupConfig,
+    pub limits: LimitsConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+    pub otlp: OtlpConfig,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+    /// used to construct absolute urls
+    pub base_url: String,
+    /// with `templates/` and `static/` directories, whose files replace the
+    /// ones embedded in the binary
+    pub assets_dir: Option<PathBuf>,
+    /// reload the templates and static files when they change, and don't let
+    /// the browsers cache them. The assets_dir defaults to the current directory.
+    pub dev: bool,
+    /// on SIGTERM or ctrl-c, how long the requests in progress (like big uploads)
+    /// have to finish before being cut
+    #[serde(with = "humantime_duration")]
+    pub shutdown_timeout: Duration,
+    /// serve https instead of http on bind_address and port
+    pub tls: Option<TlsConfig>,
+    /// also listen on a unix socket, for a reverse proxy on the same machine
+    pub unix_socket: Option<UnixSocketConfig>,
+}
+
+/// pem files, reloaded on SIGHUP
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// the certificate of the server followed by the intermediate ones
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    /// permissions of the socket, like 0o660 to let the group of the proxy use it
+    #[serde(default = "default_socket_mode")]
+    pub mode: u32,
+}
+
+fn default_socket_mode() -> u32 {
+    0o660
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1".to_string(),
+            port: 8000,
+            base_url: "https://vrac.geekingfrog.com".to_string(),
+            assets_dir: None,
+            dev: false,
+            shutdown_timeout: Duration::from_secs(30),
+            tls: None,
+            unix_socket: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub sqlite_path: PathBuf,
+    /// where the local_fs backend stores the files
+    pub local_path: PathBuf,
+    pub s3: S3Config,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            sqlite_path: PathBuf::from("./test.sqlite"),
+            local_path: PathBuf::from("/tmp/vrac/"),
+            s3: S3Config::default(),
+        }
+    }
+}
+
+/// for the garage backend. The credentials come from the usual AWS_*
+/// environment variables.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:3900".to_string(),
+            bucket: "vrac".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// base64 key to sign the cookies, at least 64 bytes.
+    /// Taken from the key file if missing.
+    pub signing_key: Option<String>,
+    /// keys used before `signing_key`, still accepted for the cookies
+    /// signed with them
+    pub previous_signing_keys: Vec<String>,
+    /// where to store the signing keys, created with a random key if it
+    /// doesn't exist. Next to the sqlite database by default.
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CleanupConfig {
+    /// time between two removals of the expired files, like "5m" or "1h 30m"
+    #[serde(with = "humantime_duration")]
+    pub interval: Duration,
+}
+
+impl Default for CleanupConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// remove the location and device information from pictures by default
+    /// for new links
+    pub strip_metadata_default: bool,
+    /// decoding images can take a lot of memory, so only do a few at once
+    pub thumbnail_concurrency: usize,
+    /// the size of the files mirrored with a fetch, when the link has no size limit
+    pub fetch_max_size_mib: i64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            strip_metadata_default: false,
+            thumbnail_concurrency: 2,
+            fetch_max_size_mib: 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// serve the prometheus metrics on /metrics
+    pub enabled: bool,
+    /// if set, /metrics requires a `Authorization: Bearer <token>` header
+    pub token: Option<String>,
+    /// serve /metrics on this address (like "127.0.0.1:9100") instead of the
+    /// main one, to keep it private
+    pub bind_address: Option<String>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            token: None,
+            bind_address: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `text` for humans, `json` for a log pipeline
+    pub format: LogFormat,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Export the spans to an OpenTelemetry collector, with otlp over http
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// like "http://localhost:4318", nothing is exported without it
+    pub endpoint: Option<String>,
+    pub service_name: String,
+    /// sent with each export, like an authorization header
+    pub headers: HashMap<String, String>,
+    /// which spans are exported, with the syntax of RUST_LOG
+    pub filter: String,
+    #[serde(with = "humantime_duration")]
+    pub timeout: Duration,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: "vrac".to_string(),
+            headers: HashMap::new(),
+            filter: "info".to_string(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file, if any, and apply the overrides from the environment.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let raw = path
+            .map(|path| {
+                std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            })
+            .transpose()?;
+        Self::parse(raw.as_deref(), std::env::vars())
+    }
+
+    /// The content of the config file, if any, and the environment variables.
+    fn parse(
+        raw: Option<&str>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut table = match raw {
+            Some(raw) => raw.parse::<toml::Table>()?,
+            None => toml::Table::new(),
+        };
+
+        for (var, value) in vars {
+            let Some((section, key)) = var
+                .strip_prefix(ENV_PREFIX)
+                .and_then(|k| k.split_once(ENV_SEPARATOR))
+            else {
+                continue;
+            };
+            let parsed = parse_env_value(&value);
+            if parsed.is_str() {
+                set_env_override(&mut table, &var, section, key, parsed)?;
+                continue;
+            }
+            // 2024 is a number for toml but can be meant for a string setting (a
+            // bucket, a token…), it's kept as a string if only that makes the config valid
+            let mut as_string = table.clone();
+            set_env_override(&mut table, &var, section, key, parsed)?;
+            if table.clone().try_into::<Config>().is_err() {
+                set_env_override(&mut as_string, &var, section, key, value.into())?;
+                if as_string.clone().try_into::<Config>().is_ok() {
+                    table = as_string;
+                }
+            }
+        }
+
+        Ok(table.try_into()?)
+    }
+
+    /// Catch the mistakes which would otherwise only show up later, when
+    /// serving requests.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key, reason: &str| ConfigError::Invalid {
+            key,
+            reason: reason.to_string(),
+        };
+
+        self.server
+            .bind_address
+            .parse::<IpAddr>()
+            .map_err(|err| invalid("server.bind_address", &err.to_string()))?;
+
+        let base_url = url::Url::parse(&self.server.base_url)
+            .map_err(|err| invalid("server.base_url", &err.to_string()))?;
+        if !matches!(base_url.scheme(), "http" | "https") {
+            return Err(invalid("server.base_url", "must be an http or https url"));
+        }
+        if self.server.base_url.ends_with('/') {
+            return Err(invalid("server.base_url", "must not end with a /"));
+        }
+
+        if let Some(tls) = &self.server.tls {
+            if !tls.cert.is_file() {
+                return Err(invalid("server.tls.cert", "is not a file"));
+            }
+            if !tls.key.is_file() {
+                return Err(invalid("server.tls.key", "is not a file"));
+            }
+        }
+        if let Some(socket) = &self.server.unix_socket {
+            let parent = socket.path.parent().filter(|p| !p.as_os_str().is_empty());
+            if parent.is_some_and(|p| !p.is_dir()) {
+                return Err(invalid(
+                    "server.unix_socket.path",
+                    "is not in an existing directory",
+                ));
+            }
+            if socket.mode > 0o777 {
+                return Err(invalid("server.unix_socket.mode", "must be at most 0o777"));
+            }
+        }
+        if let Some(dir) = &self.server.assets_dir {
+            if !dir.is_dir() {
+                return Err(invalid("server.assets_dir", "is not a directory"));
+            }
+        }
+
+        if self.storage.sqlite_path.as_os_str().is_empty() {
+            return Err(invalid("storage.sqlite_path", "cannot be empty"));
+        }
+        if self.storage.local_path.as_os_str().is_empty() {
+            return Err(invalid("storage.local_path", "cannot be empty"));
+        }
+        url::Url::parse(&self.storage.s3.endpoint)
+            .map_err(|err| invalid("storage.s3.endpoint", &err.to_string()))?;
+        if self.storage.s3.bucket.is_empty() {
+            return Err(invalid("storage.s3.bucket", "cannot be empty"));
+        }
+
+        if let Some(key) = &self.auth.signing_key {
+            decode_signing_key("auth.signing_key", key)?;
+        }
+        for key in &self.auth.previous_signing_keys {
+            decode_signing_key("auth.previous_signing_keys", key)?;
+        }
+
+        if self.cleanup.interval.is_zero() {
+            return Err(invalid("cleanup.interval", "cannot be zero"));
+        }
+        if self.limits.thumbnail_concurrency == 0 {
+            return Err(invalid(
+                "limits.thumbnail_concurrency",
+                "must be at least 1",
+            ));
+        }
+        if self.limits.fetch_max_size_mib < 1 {
+            return Err(invalid("limits.fetch_max_size_mib", "must be at least 1"));
+        }
+
+        if self.metrics.token.as_ref().is_some_and(|t| t.is_empty()) {
+            return Err(invalid("metrics.token", "cannot be empty"));
+        }
+        if let Some(address) = &self.metrics.bind_address {
+            address
+                .parse::<std::net::SocketAddr>()
+                .map_err(|err| invalid("metrics.bind_address", &err.to_string()))?;
+        }
+
+        if let Some(endpoint) = &self.otlp.endpoint {
+            let endpoint = url::Url::parse(endpoint)
+                .map_err(|err| invalid("otlp.endpoint", &err.to_string()))?;
+            if !matches!(endpoint.scheme(), "http" | "https") {
+                return Err(invalid("otlp.endpoint", "must be an http or https url"));
+            }
+        }
+        tracing_subscriber::EnvFilter::try_new(&self.otlp.filter)
+            .map_err(|err| invalid("otlp.filter", &err.to_string()))?;
+        if self.otlp.timeout.is_zero() {
+            return Err(invalid("otlp.timeout", "cannot be zero"));
+        }
+
+        Ok(())
+    }
+
+    /// the file with the signing keys
+    pub fn key_file(&self) -> PathBuf {
+        self.auth
+            .key_file
+            .clone()
+            .unwrap_or_else(|| self.storage.sqlite_path.with_extension("key"))
+    }
+}
+
+/// `name` is the setting (or file) where the key comes from, for the error.
+pub(crate) fn decode_signing_key(name: &'static str, key: &str) -> Result<Vec<u8>, ConfigError> {
+    use base64::Engine;
+
+    let key = base64::engine::general_purpose::STANDARD
+        .decode(key.trim())
+        .map_err(|err| ConfigError::Invalid {
+            key: name,
+            reason: format!("not valid base64: {err}"),
+        })?;
+    if key.len() < MIN_SIGNING_KEY_LEN {
+        return Err(ConfigError::Invalid {
+            key: name,
+            reason: format!("must be at least {MIN_SIGNING_KEY_LEN} bytes"),
+        });
+    }
+    Ok(key)
+}
+
+/// The value of an environment variable as toml if possible (numbers, booleans),
+/// as a string otherwise.
+fn parse_env_value(value: &str) -> toml::Value {
+    format!("v = {value}")
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .filter(|v| !v.is_table())
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// `VRAC_STORAGE__S3__BUCKET=x` sets `storage.s3.bucket`.
+fn set_env_override(
+    table: &mut toml::Table,
+    var: &str,
+    section: &str,
+    key: &str,
+    value: toml::Value,
+) -> Result<(), ConfigError> {
+    let mut path: Vec<String> = std::iter::once(section)
+        .chain(key.split(ENV_SEPARATOR))
+        .map(str::to_lowercase)
+        .collect();
+    let last = path.pop().unwrap_or_default();
+
+    let mut current = table;
+    for part in path {
+        let entry = current
+            .entry(part)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        current = match entry {
+            toml::Value::Table(t) => t,
+            _ => {
+                return Err(ConfigError::Env {
+                    var: var.to_string(),
+                    reason: "doesn't point to a setting".to_string(),
+                })
+            }
+        };
+    }
+
+    current.insert(last, value);
+    Ok(())
+}
+
+/// durations written like "5m" or "1h 30m"
+mod humantime_duration {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub(super) fn serialize<S: Serializer>(d: &Duration, ser: S) -> Result<S::Ok, S::Error> {
+        ser.serialize_str(&humantime::format_duration(*d).to_string())
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Duration, D::Error> {
+        let raw = String::deserialize(de)?;
+        humantime::parse_duration(&raw).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: Option<&str>, vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        Config::parse(raw, vars)
+    }
+
+    #[test]
+    fn overrides_the_file() {
+        let raw = "[server]\nport = 9000\nbind_address = \"0.0.0.0\"\n";
+        let config = parse(
+            Some(raw),
+            &[
+                ("VRAC_SERVER__PORT", "8080"),
+                ("VRAC_LIMITS__STRIP_METADATA_DEFAULT", "true"),
+                ("VRAC_SERVER__SHUTDOWN_TIMEOUT", "1m 30s"),
+                ("VRAC_STORAGE__S3__ENDPOINT", "http://localhost:3900"),
+                // not for vrac
+                ("HOME", "/root"),
+                ("VRAC_NOSEPARATOR", "1"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.bind_address, "0.0.0.0");
+        assert!(config.limits.strip_metadata_default);
+        assert_eq!(config.server.shutdown_timeout, Duration::from_secs(90));
+        assert_eq!(config.storage.s3.endpoint, "http://localhost:3900");
+    }
+
+    #[test]
+    fn numbers_for_strings() {
+        let config = parse(
+            None,
+            &[
+                ("VRAC_METRICS__TOKEN", "123456"),
+                ("VRAC_STORAGE__S3__BUCKET", "2024"),
+                ("VRAC_SERVER__PORT", "8080"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.metrics.token.as_deref(), Some("123456"));
+        assert_eq!(config.storage.s3.bucket, "2024");
+        assert_eq!(config.server.port, 8080);
+    }
+
+    #[test]
+    fn invalid_overrides() {
+        let err = parse(None, &[("VRAC_SERVER__PORT", "http")]).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(_)), "{err}");
+
+        let err = parse(None, &[("VRAC_SERVER__PORT", "99999")]).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(_)), "{err}");
+
+        let err = parse(None, &[("VRAC_SERVER__NOPE", "1")]).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(_)), "{err}");
+
+        let err = parse(
+            Some("[server]\nport = 8000\n"),
+            &[("VRAC_SERVER__PORT__X", "1")],
+        )
+        .unwrap_err();
+        assert!(matches!(err, ConfigError::Env { .. }), "{err}");
+    }
+}
@@ -46,6 +46,32 @@ pub(crate) struct DbToken {
 
     /// an identifier for the type of storage to use for this token.
     pub(crate) backend_type: String,
+
+    /// comma separated list of accepted mime types (image/png), mime prefixes (image/*)
+    /// and file extensions (.pdf). Anything is accepted when not set.
+    pub(crate) allowed_types: Option<String>,
+
+    /// at most that many files can be uploaded with this token
+    pub(crate) max_files: Option<i64>,
+
+    /// at most that many MiB for any single file associated with this token
+    pub(crate) max_file_size_mib: Option<i64>,
+
+    /// an inbox token accepts many uploads until valid_until, each one recorded
+    /// as a separate batch, instead of being used by the first upload.
+    pub(crate) multi_use: bool,
+
+    /// human friendly title, used instead of the path when displaying the token
+    pub(crate) title: Option<String>,
+
+    /// markdown text set by the admin to explain what the link is for
+    pub(crate) description: Option<String>,
+
+    /// remove the location and device information from uploaded pictures
+    pub(crate) strip_metadata: bool,
+
+    /// when stripping metadata, also keep the unmodified files for the admins
+    pub(crate) keep_original: bool,
 }
 
 #[derive(Debug)]
@@ -55,6 +81,35 @@ pub(crate) struct CreateToken<'input> {
     pub(crate) valid_until: OffsetDateTime,
     pub(crate) content_expires_after_hours: Option<i64>,
     pub(crate) backend_type: &'input str,
+    pub(crate) allowed_types: Option<&'input str>,
+    pub(crate) max_files: Option<i64>,
+    pub(crate) max_file_size_mib: Option<i64>,
+    pub(crate) multi_use: bool,
+    pub(crate) title: Option<&'input str>,
+    pub(crate) description: Option<&'input str>,
+    pub(crate) strip_metadata: bool,
+    pub(crate) keep_original: bool,
+}
+
+/// A successful upload for a token, with the files sharing its attempt_counter
+#[derive(sqlx::FromRow, Debug)]
+#[allow(dead_code)]
+pub(crate) struct DbUploadBatch {
+    pub(crate) id: i64,
+    pub(crate) token_id: i64,
+    pub(crate) attempt_counter: i64,
+    pub(crate) uploader_name: Option<String>,
+    pub(crate) message: Option<String>,
+    pub(crate) created_at: OffsetDateTime,
+    pub(crate) uploader_email: Option<String>,
+}
+
+/// Optional information given by the person uploading some files
+#[derive(Debug, Default)]
+pub(crate) struct UploaderInfo {
+    pub(crate) name: Option<String>,
+    pub(crate) email: Option<String>,
+    pub(crate) message: Option<String>,
 }
 
 #[derive(sqlx::FromRow, Debug)]
@@ -70,10 +125,25 @@ pub struct DbFile {
     pub completed_at: Option<OffsetDateTime>,
 }
 
+/// A blob generated from an uploaded file, like a thumbnail
+#[derive(sqlx::FromRow, Debug)]
+#[allow(dead_code)]
+pub(crate) struct DbDerivedFile {
+    pub(crate) id: i64,
+    pub(crate) file_id: i64,
+    pub(crate) kind: String,
+    pub(crate) mime_type: Option<String>,
+    pub(crate) backend_type: String,
+    pub(crate) backend_data: String,
+    pub(crate) created_at: OffsetDateTime,
+}
+
 #[derive(sqlx::FromRow, Debug)]
 pub struct DbFileMetadata {
     pub size_b: Option<i64>,
     pub mime_type: Option<String>,
+    /// when the picture was taken, according to its EXIF data
+    pub captured_at: Option<OffsetDateTime>,
     // TODO: would be cool to have a sha256
 }
 
@@ -90,6 +160,7 @@ struct FileAndMetadata {
     created_at: OffsetDateTime,
     completed_at: Option<OffsetDateTime>,
     size_b: Option<i64>,
+    captured_at: Option<OffsetDateTime>,
 }
 
 impl std::convert::From<FileAndMetadata> for (DbFile, DbFileMetadata) {
@@ -109,6 +180,7 @@ impl std::convert::From<FileAndMetadata> for (DbFile, DbFileMetadata) {
             DbFileMetadata {
                 size_b: x.size_b,
                 mime_type: x.mime_type,
+                captured_at: x.captured_at,
             },
         )
     }
@@ -184,10 +256,48 @@ impl DBService {
     /// close the underlying connection pool. This is required when
     /// running a short query in a self contained binary, since
     /// some transaction may not have been flushed to disk yet
+    #[tracing::instrument(skip_all)]
     pub async fn close(&self) {
+        // move everything from the WAL into the db file, so that it is complete
+        // on its own, for backups for example
+        if let Err(err) = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&self.pool)
+            .await
+        {
+            tracing::warn!("Cannot checkpoint the sqlite WAL: {err}");
+        }
         self.pool.close().await;
     }
 
+    /// for the readiness check
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .with_context(|| "cannot reach the database".to_string())?;
+        Ok(())
+    }
+
+    /// (open connections, idle connections)
+    pub(crate) fn pool_stats(&self) -> (u32, usize) {
+        (self.pool.size(), self.pool.num_idle())
+    }
+
+    /// total size of the files, per storage backend
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn stored_bytes_per_backend(&self) -> Result<Vec<(String, i64)>> {
+        sqlx::query_as::<_, (String, i64)>(
+            "SELECT f.backend_type, COALESCE(SUM(m.size_b), 0) FROM file AS f
+            INNER JOIN file_metadata AS m ON m.file_id = f.id
+            GROUP BY f.backend_type",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .with_context(|| "cannot get the stored bytes per backend".to_string())
+    }
+
+    #[tracing::instrument(skip_all)]
     pub async fn migrate(&self) -> Result<()> {
         tracing::info!("starting migration");
         sqlx::migrate!("./migrations").run(&self.pool).await?;
@@ -196,15 +306,52 @@ impl DBService {
     }
 
     /// a non deleted token that can be used to upload some files.
+    #[tracing::instrument(skip_all)]
     pub(crate) async fn get_valid_token(&self, path: &str) -> Result<GetTokenResult> {
         get_valid_token(&self.pool, path).await
     }
 
     /// a non deleted token already associated with files.
+    #[tracing::instrument(skip_all, fields(file_id = file_id))]
     pub(crate) async fn get_valid_file(&self, path: &str, file_id: i64) -> Result<Option<DbFile>> {
         get_valid_file(&self.pool, path, file_id).await
     }
 
+    /// a file uploaded to an inbox token, as part of a successful batch
+    #[tracing::instrument(skip_all, fields(file_id = file_id))]
+    pub(crate) async fn get_valid_batch_file(
+        &self,
+        path: &str,
+        file_id: i64,
+    ) -> Result<Option<DbFile>> {
+        let now = time::OffsetDateTime::now_utc();
+
+        sqlx::query_as::<_, DbFile>(
+            "SELECT f.* from file as f
+            INNER JOIN token as t ON f.token_id = t.id
+            INNER JOIN upload_batch as b
+                ON b.token_id = t.id AND b.attempt_counter = f.attempt_counter
+            WHERE t.path=?
+            AND f.id=?
+            AND t.deleted_at IS NULL
+            AND t.multi_use
+            AND (t.content_expires_at IS NULL OR t.content_expires_at > ?)
+            AND f.completed_at IS NOT NULL",
+        )
+        .bind(path)
+        .bind(file_id)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await
+        .with_context(|| {
+            format!(
+                "cannot select a valid batch file for token at path {} and file id {}",
+                path, file_id
+            )
+        })
+    }
+
+    #[tracing::instrument(skip_all, fields(token_id = token_id))]
     pub async fn get_files(
         &self,
         token_id: i64,
@@ -222,6 +369,7 @@ impl DBService {
         Ok(res)
     }
 
+    #[tracing::instrument(skip_all)]
     pub(crate) async fn create_token<'input>(
         &self,
         ct: CreateToken<'input>,
@@ -248,8 +396,10 @@ impl DBService {
 
         let tok = sqlx::query_as::<_, DbToken>(
             "INSERT INTO token
-            (path, max_size_mib, valid_until, content_expires_after_hours, backend_type)
-            VALUES (?,?,?,?,?)
+            (path, max_size_mib, valid_until, content_expires_after_hours, backend_type,
+             allowed_types, max_files, max_file_size_mib, multi_use, title, description,
+             strip_metadata, keep_original)
+            VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?)
             RETURNING *",
         )
         .bind(ct.path)
@@ -257,6 +407,14 @@ impl DBService {
         .bind(ct.valid_until)
         .bind(ct.content_expires_after_hours)
         .bind(ct.backend_type)
+        .bind(ct.allowed_types)
+        .bind(ct.max_files)
+        .bind(ct.max_file_size_mib)
+        .bind(ct.multi_use)
+        .bind(ct.title)
+        .bind(ct.description)
+        .bind(ct.strip_metadata)
+        .bind(ct.keep_original)
         .fetch_one(&mut *tx)
         .await
         .with_context(|| format!("cannot create token for path {}", ct.path))?;
@@ -269,6 +427,7 @@ impl DBService {
         Ok(Ok(tok))
     }
 
+    #[tracing::instrument(skip_all)]
     pub(crate) async fn initiate_upload(&self, token: DbToken) -> Result<UploadToken> {
         let now = time::OffsetDateTime::now_utc();
 
@@ -284,7 +443,7 @@ impl DBService {
             WHERE id=?
             AND deleted_at IS NULL
             AND valid_until > ?
-            AND used_at IS NULL
+            AND (used_at IS NULL OR multi_use)
             ",
         )
         .bind(token.id)
@@ -319,6 +478,7 @@ impl DBService {
         })
     }
 
+    #[tracing::instrument(skip_all)]
     pub(crate) async fn create_file(
         &self,
         ut: &UploadToken,
@@ -352,6 +512,7 @@ impl DBService {
         Ok(f)
     }
 
+    #[tracing::instrument(skip_all)]
     pub(crate) async fn finalise_file_upload(
         &self,
         file: DbFile,
@@ -383,10 +544,11 @@ impl DBService {
             .await
             .with_context(|| format!("error finalising file upload for id {}", file.id))?;
 
-        sqlx::query("INSERT INTO file_metadata (file_id, size_b, mime_type) VALUES (?, ?, ?)")
+        sqlx::query("INSERT INTO file_metadata (file_id, size_b, mime_type, captured_at) VALUES (?, ?, ?, ?)")
             .bind(file.id)
             .bind(metadata.size_b)
             .bind(metadata.mime_type)
+            .bind(metadata.captured_at)
             .execute(&mut *tx)
             .await
             .with_context(|| {
@@ -406,47 +568,252 @@ impl DBService {
         Ok(())
     }
 
-    pub(crate) async fn finalise_token_upload(&self, ut: UploadToken) -> Result<()> {
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn finalise_token_upload(
+        &self,
+        ut: UploadToken,
+        uploader: UploaderInfo,
+    ) -> Result<()> {
         let now = time::OffsetDateTime::now_utc();
+        let mut tx = self.pool.begin().await.with_context(|| {
+            format!(
+                "cannot begin transaction to finalise upload for token {}",
+                ut.id
+            )
+        })?;
+
         let token = sqlx::query_as::<_, DbToken>("SELECT * from token where id=?")
             .bind(ut.id)
-            .fetch_one(&self.pool)
+            .fetch_one(&mut *tx)
             .await
             .with_context(|| format!("cannot find token to finalise upload for id {}", ut.id))?;
 
+        // for an inbox, the content is kept for the given duration after the token
+        // stops accepting uploads, so that the last batch has the same guarantee
+        // as the first one.
+        let expires_from = if token.multi_use {
+            token.valid_until
+        } else {
+            now
+        };
         let expires_at = token
             .content_expires_after_hours
-            .map(|h| now + std::time::Duration::from_secs(3600 * (h as u64)));
+            .map(|h| expires_from + std::time::Duration::from_secs(3600 * (h as u64)));
 
-        let x = sqlx::query(
-            "UPDATE token SET used_at=?, content_expires_at=? WHERE id=? AND attempt_counter=?",
+        let x = if token.multi_use {
+            sqlx::query(
+                "UPDATE token SET used_at=COALESCE(used_at, ?), content_expires_at=? WHERE id=?",
+            )
+            .bind(now)
+            .bind(expires_at)
+            .bind(ut.id)
+            .execute(&mut *tx)
+            .await
+        } else {
+            sqlx::query(
+                "UPDATE token SET used_at=?, content_expires_at=? WHERE id=? AND attempt_counter=?",
+            )
+            .bind(now)
+            .bind(expires_at)
+            .bind(ut.id)
+            // need to add the attempt counter in the where to avoid races if two
+            // concurrent uploads (vanishingly unlikely)
+            .bind(ut.attempt_counter)
+            .execute(&mut *tx)
+            .await
+        }
+        .with_context(|| format!("cannot finalize token upload for id {}", ut.id))?;
+
+        tracing::info!("return from the update finalise token {:?}", x);
+
+        sqlx::query(
+            "INSERT INTO upload_batch
+            (token_id, attempt_counter, uploader_name, uploader_email, message)
+            VALUES (?,?,?,?,?)",
         )
-        .bind(now)
-        .bind(expires_at)
         .bind(ut.id)
-        // need to add the attempt counter in the where to avoid races if two
-        // concurrent uploads (vanishingly unlikely)
         .bind(ut.attempt_counter)
-        .execute(&self.pool)
+        .bind(uploader.name)
+        .bind(uploader.email)
+        .bind(uploader.message)
+        .execute(&mut *tx)
         .await
-        .with_context(|| format!("cannot finalize token upload for id {}", ut.id))?;
+        .with_context(|| {
+            format!(
+                "cannot record upload batch for token {} and attempt {}",
+                ut.id, ut.attempt_counter
+            )
+        })?;
 
-        tracing::info!("return from the update finalise token {:?}", x);
+        tx.commit().await.with_context(|| {
+            format!(
+                "cannot commit transaction to finalise upload for token {}",
+                ut.id
+            )
+        })?;
+
+        Ok(())
+    }
+
+    /// The successful upload for the given token and attempt, if any
+    #[tracing::instrument(skip_all, fields(token_id = token_id))]
+    pub(crate) async fn get_batch(
+        &self,
+        token_id: i64,
+        attempt_counter: i64,
+    ) -> Result<Option<DbUploadBatch>> {
+        sqlx::query_as::<_, DbUploadBatch>(
+            "SELECT * FROM upload_batch WHERE token_id=? AND attempt_counter=?",
+        )
+        .bind(token_id)
+        .bind(attempt_counter)
+        .fetch_optional(&self.pool)
+        .await
+        .with_context(|| {
+            format!("cannot get upload batch for token {token_id} and attempt {attempt_counter}")
+        })
+    }
+
+    /// All the successful uploads for the given token, oldest first
+    #[tracing::instrument(skip_all, fields(token_id = token_id))]
+    pub(crate) async fn get_batches(&self, token_id: i64) -> Result<Vec<DbUploadBatch>> {
+        sqlx::query_as::<_, DbUploadBatch>(
+            "SELECT * FROM upload_batch WHERE token_id=? ORDER BY attempt_counter",
+        )
+        .bind(token_id)
+        .fetch_all(&self.pool)
+        .await
+        .with_context(|| format!("cannot get upload batches for token {token_id}"))
+    }
+
+    /// None if the file has been deleted in the meantime, by the cleanup
+    #[tracing::instrument(skip_all, fields(file_id = file_id))]
+    pub(crate) async fn create_derived_file(
+        &self,
+        file_id: i64,
+        kind: &str,
+        mime_type: &str,
+        backend_type: &str,
+        backend_data: String,
+    ) -> Result<Option<DbDerivedFile>> {
+        sqlx::query_as::<_, DbDerivedFile>(
+            "INSERT INTO derived_file
+            (file_id, kind, mime_type, backend_type, backend_data)
+            SELECT ?,?,?,?,? WHERE EXISTS (SELECT 1 FROM file WHERE id = ?)
+            RETURNING *",
+        )
+        .bind(file_id)
+        .bind(kind)
+        .bind(mime_type)
+        .bind(backend_type)
+        .bind(backend_data)
+        .bind(file_id)
+        .fetch_optional(&self.pool)
+        .await
+        .with_context(|| format!("cannot create derived file {kind} for file {file_id}"))
+    }
+
+    #[tracing::instrument(skip_all, fields(file_id = file_id))]
+    pub(crate) async fn get_derived_file(
+        &self,
+        file_id: i64,
+        kind: &str,
+    ) -> Result<Option<DbDerivedFile>> {
+        sqlx::query_as::<_, DbDerivedFile>("SELECT * FROM derived_file WHERE file_id=? AND kind=?")
+            .bind(file_id)
+            .bind(kind)
+            .fetch_optional(&self.pool)
+            .await
+            .with_context(|| format!("cannot get derived file {kind} for file {file_id}"))
+    }
+
+    /// set once the exif data has been read, after the upload
+    #[tracing::instrument(skip_all, fields(file_id = file_id))]
+    pub(crate) async fn set_file_captured_at(
+        &self,
+        file_id: i64,
+        captured_at: OffsetDateTime,
+    ) -> Result<()> {
+        sqlx::query("UPDATE file_metadata SET captured_at=? WHERE file_id=?")
+            .bind(captured_at)
+            .bind(file_id)
+            .execute(&self.pool)
+            .await
+            .with_context(|| format!("cannot set capture time for file {file_id}"))?;
+        Ok(())
+    }
 
+    /// where a file mirrored from another server comes from
+    #[tracing::instrument(skip_all, fields(file_id = file_id))]
+    pub(crate) async fn set_file_source(
+        &self,
+        file_id: i64,
+        source_url: &str,
+        etag: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query("UPDATE file_metadata SET source_url=?, etag=? WHERE file_id=?")
+            .bind(source_url)
+            .bind(etag)
+            .bind(file_id)
+            .execute(&self.pool)
+            .await
+            .with_context(|| format!("cannot set source url for file {file_id}"))?;
         Ok(())
     }
 
+    /// all the blobs generated from the given file
+    #[tracing::instrument(skip_all, fields(file_id = file_id))]
+    pub(crate) async fn get_derived_files(&self, file_id: i64) -> Result<Vec<DbDerivedFile>> {
+        sqlx::query_as::<_, DbDerivedFile>("SELECT * FROM derived_file WHERE file_id=?")
+            .bind(file_id)
+            .fetch_all(&self.pool)
+            .await
+            .with_context(|| format!("cannot get derived files for file {file_id}"))
+    }
+
+    /// ids of the files of the given upload which have a generated blob of the given kind
+    #[tracing::instrument(skip_all, fields(token_id = token_id))]
+    pub(crate) async fn get_file_ids_with_derived(
+        &self,
+        token_id: i64,
+        attempt_counter: i64,
+        kind: &str,
+    ) -> Result<Vec<i64>> {
+        sqlx::query_scalar::<_, i64>(
+            "SELECT f.id FROM file AS f
+            INNER JOIN derived_file AS d ON d.file_id = f.id
+            WHERE f.token_id=? AND f.attempt_counter=? AND d.kind=?",
+        )
+        .bind(token_id)
+        .bind(attempt_counter)
+        .bind(kind)
+        .fetch_all(&self.pool)
+        .await
+        .with_context(|| {
+            format!("cannot get derived {kind} for token {token_id} and attempt {attempt_counter}")
+        })
+    }
+
+    #[tracing::instrument(skip_all)]
     pub(crate) async fn get_files_to_delete(&self, now: &OffsetDateTime) -> Result<Vec<DbFile>> {
         sqlx::query_as::<_, DbFile>(
             "SELECT f.* from file as f
             INNER JOIN token as t
             ON t.id = f.token_id
             WHERE (t.content_expires_at <= ?)
-            OR (t.attempt_counter > f.attempt_counter)
-            OR (used_at IS NULL AND valid_until <= ?)",
+            OR (t.attempt_counter > f.attempt_counter AND NOT t.multi_use)
+            OR (used_at IS NULL AND valid_until <= ?)
+            -- an inbox can have concurrent uploads, so files from failed attempts
+            -- are only removed once it stops accepting new ones.
+            OR (t.multi_use AND t.valid_until <= ? AND NOT EXISTS (
+                SELECT 1 FROM upload_batch AS b
+                WHERE b.token_id = f.token_id
+                AND b.attempt_counter = f.attempt_counter
+            ))",
         )
         .bind(now)
         .bind(now)
+        .bind(now)
         .fetch_all(&self.pool)
         .await
         .with_context(|| "failed to fetch files to delete".to_string())
@@ -454,10 +821,31 @@ impl DBService {
 
     /// Delete the token in DB that are expired (used or not)
     /// This doesn't do anything with the potential associated files.
+    #[tracing::instrument(skip_all)]
     pub(crate) async fn delete_expired_tokens(
         &self,
         now: &OffsetDateTime,
     ) -> Result<Vec<(i64, String)>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .with_context(|| "Cannot begin transaction to delete expired tokens")?;
+
+        sqlx::query(
+            "DELETE from upload_batch
+            WHERE token_id IN (
+                SELECT id FROM token
+                WHERE (content_expires_at <= ?)
+                OR (used_at IS NULL AND valid_until <= ?)
+            )",
+        )
+        .bind(now)
+        .bind(now)
+        .execute(&mut *tx)
+        .await
+        .with_context(|| "Cannot delete upload batches of expired tokens")?;
+
         let deleted_ids = sqlx::query_as::<_, (i64, String)>(
             "DELETE from token
             WHERE (content_expires_at <= ?)
@@ -466,13 +854,18 @@ impl DBService {
         )
         .bind(now)
         .bind(now)
-        .fetch_all(&self.pool)
+        .fetch_all(&mut *tx)
         .await
         .with_context(|| "Cannot delete expired tokens")?;
+
+        tx.commit()
+            .await
+            .with_context(|| "Cannot commit transaction to delete expired tokens")?;
         Ok(deleted_ids)
     }
 
     /// Remove from the DB the files for the given ids
+    #[tracing::instrument(skip_all)]
     pub(crate) async fn delete_files<Ids>(&self, ids: Ids) -> Result<()>
     where
         Ids: IntoIterator<Item = i64>,
@@ -488,6 +881,12 @@ impl DBService {
         // something different, and at that point, this doc may be handy:
         // https://github.com/launchbadge/sqlx/blob/main/FAQ.md#how-can-i-do-a-select--where-foo-in--query
         for id in ids {
+            sqlx::query("DELETE from derived_file where file_id = ?")
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .with_context(|| format!("Cannot delete derived files for file id {id}"))?;
+
             sqlx::query("DELETE from file_metadata where file_id = ?")
                 .bind(id)
                 .execute(&mut *tx)
@@ -507,6 +906,7 @@ impl DBService {
         Ok(())
     }
 
+    #[tracing::instrument(skip_all)]
     pub(crate) async fn get_account(&self, username: &str) -> Result<Option<Account>> {
         sqlx::query_as::<_, Account>("SELECT * from account where username = ?")
             .bind(username)
@@ -515,6 +915,7 @@ impl DBService {
             .with_context(|| format!("Unable to find account with username {}", username))
     }
 
+    #[tracing::instrument(skip_all)]
     pub async fn create_account(&self, username: &str, phc: &str) -> Result<Account> {
         sqlx::query_as::<_, Account>(
             "INSERT INTO account
@@ -528,6 +929,7 @@ impl DBService {
         .with_context(|| format!("Unable to create account with username {username}"))
     }
 
+    #[tracing::instrument(skip_all)]
     pub async fn change_password(&self, username: &str, phc: &str) -> Result<Account> {
         sqlx::query_as::<_, Account>(
             "UPDATE account
@@ -602,7 +1004,21 @@ where
     // but by construction, at any point in time, there can only be one token that at most
     // that is either fresh or used and not yet expired
     for tok in tokens {
-        if tok.used_at.is_none() {
+        if tok.multi_use {
+            // an inbox keeps accepting uploads until it's no longer valid, and the
+            // content only makes sense once something was uploaded.
+            if tok.valid_until > now {
+                return Ok(GetTokenResult::Fresh(tok));
+            }
+            match (tok.used_at, tok.content_expires_at) {
+                (None, _) => (),
+                (Some(_), None) => return Ok(GetTokenResult::Used(tok)),
+                (Some(_), Some(expires_at)) if expires_at > now => {
+                    return Ok(GetTokenResult::Used(tok))
+                }
+                _ => (),
+            }
+        } else if tok.used_at.is_none() {
             return Ok(GetTokenResult::Fresh(tok));
         } else {
             let now = OffsetDateTime::now_utc();
@@ -629,6 +1045,7 @@ where
         AND f.id=?
         AND t.deleted_at IS NULL
         AND t.used_at IS NOT NULL
+        AND NOT t.multi_use
         AND (t.content_expires_after_hours IS NULL
             OR t.content_expires_at > ?
         )
@@ -0,0 +1,48 @@
+//! `--dev`: reloads the templates and static files when they change on disk.
+
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+
+use crate::state::AppState;
+
+/// editors often save a file in several steps, wait for all of them
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Watch the templates/ and static/ directories of the assets directory, until
+/// the server stops.
+pub async fn watch_assets(state: AppState) -> notify::Result<()> {
+    let Some(dir) = state.assets_dir.clone() else {
+        return Ok(());
+    };
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    // the watcher calls this from its own thread
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    for sub_dir in ["templates", "static"] {
+        let path = dir.join(sub_dir);
+        if path.is_dir() {
+            watcher.watch(&path, RecursiveMode::Recursive)?;
+        }
+    }
+    tracing::info!("Reloading the assets in {} when they change", dir.display());
+
+    while let Some(event) = rx.recv().await {
+        let event: notify::Event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                tracing::warn!("Error watching the assets: {err}");
+                continue;
+            }
+        };
+        if event.kind.is_access() {
+            continue;
+        }
+        tokio::time::sleep(DEBOUNCE).await;
+        while rx.try_recv().is_ok() {}
+        state.reload_assets();
+    }
+    Ok(())
+}
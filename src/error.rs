@@ -62,6 +62,15 @@ pub enum AppError {
     #[error("Cannot delete remote blob")]
     S3DeleteError(#[from] s3::error::SdkError<s3::operation::delete_object::DeleteObjectError>),
 
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+
+    #[error("Cannot generate thumbnail: {0}")]
+    ThumbnailError(String),
+
+    #[error("Cannot highlight text: {0}")]
+    HighlightError(#[from] syntect::Error),
+
     #[error("Delete blob failed for file id {file_id} and token id {token_id}")]
     DeleteBlobError {
         file_id: i64,
@@ -0,0 +1,311 @@
+//! Download of a remote file by the server, to mirror it in a link.
+
+use std::{io::ErrorKind, time::Duration};
+
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use hyper::{body::HttpBody, client::HttpConnector, header, Body, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+
+/// handles both http and https urls
+pub(crate) type HttpClient = hyper::Client<HttpsConnector<HttpConnector>>;
+
+/// a few redirects are common (http -> https, download mirrors…), more is likely a loop
+const MAX_REDIRECTS: usize = 5;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// until the headers are received
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+/// between two chunks of the body, a big file can take as long as it needs
+/// but a stalled server shouldn't keep the upload open forever
+pub(crate) const BODY_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) fn client() -> HttpClient {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(CONNECT_TIMEOUT));
+    hyper::Client::builder().build(HttpsConnector::new_with_connector(http))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum FetchError {
+    #[error("Invalid url {url}: {reason}")]
+    InvalidUrl { url: String, reason: String },
+
+    #[error("Cannot fetch {url}: {source}")]
+    Request { url: String, source: hyper::Error },
+
+    #[error("Cannot fetch {url}, got status {status}")]
+    Status { url: String, status: StatusCode },
+
+    #[error("Cannot fetch {0}, no response in time")]
+    Timeout(String),
+
+    #[error("Too many redirects when fetching {0}")]
+    TooManyRedirects(String),
+}
+
+/// The response for a remote file, the body hasn't been read yet.
+#[derive(Debug)]
+pub(crate) struct RemoteFile {
+    /// after following the redirects
+    pub(crate) url: String,
+    pub(crate) file_name: String,
+    pub(crate) mime_type: Option<String>,
+    pub(crate) etag: Option<String>,
+    pub(crate) body: Body,
+}
+
+/// GET the given url, following the redirects.
+pub(crate) async fn fetch(client: &HttpClient, url: &str) -> Result<RemoteFile, FetchError> {
+    let mut uri = parse_uri(url)?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let response = tokio::time::timeout(RESPONSE_TIMEOUT, client.get(uri.clone()))
+            .await
+            .map_err(|_| FetchError::Timeout(uri.to_string()))?
+            .map_err(|source| FetchError::Request {
+                url: uri.to_string(),
+                source,
+            })?;
+        let status = response.status();
+
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .ok_or_else(|| FetchError::Status {
+                    url: uri.to_string(),
+                    status,
+                })?;
+            // the location can be relative to the current url
+            let next = url::Url::parse(&uri.to_string())
+                .and_then(|base| base.join(location))
+                .map_err(|err| FetchError::InvalidUrl {
+                    url: location.to_string(),
+                    reason: err.to_string(),
+                })?;
+            uri = parse_uri(next.as_str())?;
+            continue;
+        }
+
+        if !status.is_success() {
+            return Err(FetchError::Status {
+                url: uri.to_string(),
+                status,
+            });
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(str::to_string)
+        };
+        let mime_type = header(header::CONTENT_TYPE)
+            .and_then(|m| m.split(';').next().map(|m| m.trim().to_string()))
+            .filter(|m| !m.is_empty());
+        let etag = header(header::ETAG);
+        let file_name = header(header::CONTENT_DISPOSITION)
+            .as_deref()
+            .and_then(disposition_file_name)
+            .or_else(|| url_file_name(&uri))
+            .unwrap_or_else(|| "download".to_string());
+
+        return Ok(RemoteFile {
+            url: uri.to_string(),
+            file_name,
+            mime_type,
+            etag,
+            body: response.into_body(),
+        });
+    }
+
+    Err(FetchError::TooManyRedirects(url.to_string()))
+}
+
+/// The content of the remote file, failing with `ErrorKind::TimedOut` if the
+/// remote server sends nothing for `idle_timeout`. Ends after the first error.
+pub(crate) fn body_stream(
+    body: Body,
+    idle_timeout: Duration,
+) -> BoxStream<'static, std::io::Result<Bytes>> {
+    futures::stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match tokio::time::timeout(idle_timeout, body.data()).await {
+            Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some(body))),
+            Ok(Some(Err(err))) => Some((Err(std::io::Error::other(err)), None)),
+            Ok(None) => None,
+            Err(_) => {
+                let err = std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("nothing received for {}s", idle_timeout.as_secs_f32()),
+                );
+                Some((Err(err), None))
+            }
+        }
+    })
+    .boxed()
+}
+
+fn parse_uri(url: &str) -> Result<Uri, FetchError> {
+    let uri: Uri =
+        url.parse()
+            .map_err(|err: hyper::http::uri::InvalidUri| FetchError::InvalidUrl {
+                url: url.to_string(),
+                reason: err.to_string(),
+            })?;
+    match uri.scheme_str() {
+        Some("http" | "https") => Ok(uri),
+        _ => Err(FetchError::InvalidUrl {
+            url: url.to_string(),
+            reason: "only http and https are supported".to_string(),
+        }),
+    }
+}
+
+/// `attachment; filename="some file.pdf"`, the extended `filename*` form isn't handled.
+fn disposition_file_name(disposition: &str) -> Option<String> {
+    disposition
+        .split(';')
+        .filter_map(|part| part.trim().strip_prefix("filename="))
+        .map(|name| name.trim_matches('"').replace(['/', '\\'], "_"))
+        .find(|name| !name.is_empty())
+}
+
+/// last segment of the path of the url, decoded
+fn url_file_name(uri: &Uri) -> Option<String> {
+    let segment = uri.path().rsplit('/').next()?;
+    let name = urlencoding::decode(segment).ok()?;
+    let name = name.replace(['/', '\\'], "_");
+    (!name.is_empty()).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Request, Response,
+    };
+
+    use super::*;
+
+    async fn remote(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let response = Response::builder();
+        let response = match req.uri().path() {
+            "/start" => {
+                let host = req.headers()[header::HOST].to_str().unwrap();
+                response
+                    .status(StatusCode::FOUND)
+                    .header(header::LOCATION, format!("http://{host}/mirror/"))
+            }
+            "/mirror/" => response
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, "../files/report"),
+            "/files/report" => response
+                .header(header::CONTENT_TYPE, "application/pdf; charset=binary")
+                .header(
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"Q3 report.pdf\"",
+                )
+                .header(header::ETAG, "\"v1\""),
+            "/files/some%20notes.txt" => response,
+            "/stalled" => {
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    sender.send_data("start".into()).await.unwrap();
+                    // keeps the response open without sending anything
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    drop(sender);
+                });
+                return Ok(response.body(body).unwrap());
+            }
+            "/loop" => response
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, "/loop"),
+            _ => response.status(StatusCode::NOT_FOUND),
+        };
+        Ok(response.body(Body::from("content")).unwrap())
+    }
+
+    /// a local server, returns its base url
+    fn serve() -> String {
+        let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(remote)) });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn follows_redirects() {
+        let base = serve();
+        let remote = fetch(&client(), &format!("{base}/start")).await.unwrap();
+
+        // the second location is relative
+        assert_eq!(remote.url, format!("{base}/files/report"));
+        assert_eq!(remote.file_name, "Q3 report.pdf");
+        assert_eq!(remote.mime_type.as_deref(), Some("application/pdf"));
+        assert_eq!(remote.etag.as_deref(), Some("\"v1\""));
+        let body = hyper::body::to_bytes(remote.body).await.unwrap();
+        assert_eq!(&body[..], b"content");
+    }
+
+    #[tokio::test]
+    async fn file_name_from_the_url() {
+        let base = serve();
+        let remote = fetch(&client(), &format!("{base}/files/some%20notes.txt"))
+            .await
+            .unwrap();
+
+        assert_eq!(remote.file_name, "some notes.txt");
+        assert_eq!(remote.mime_type, None);
+        assert_eq!(remote.etag, None);
+    }
+
+    #[tokio::test]
+    async fn redirect_loop() {
+        let base = serve();
+        let err = fetch(&client(), &format!("{base}/loop")).await.unwrap_err();
+
+        assert!(matches!(err, FetchError::TooManyRedirects(url) if url == format!("{base}/loop")));
+    }
+
+    #[tokio::test]
+    async fn error_status() {
+        let base = serve();
+        let err = fetch(&client(), &format!("{base}/missing"))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            FetchError::Status { url, status: StatusCode::NOT_FOUND } if url == format!("{base}/missing")
+        ));
+    }
+
+    #[tokio::test]
+    async fn stalled_body() {
+        let base = serve();
+        let remote = fetch(&client(), &format!("{base}/stalled")).await.unwrap();
+        let mut body = body_stream(remote.body, Duration::from_millis(200));
+
+        assert_eq!(&body.next().await.unwrap().unwrap()[..], b"start");
+        let err = body.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn only_http() {
+        let err = fetch(&client(), "ftp://example.com/file")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, FetchError::InvalidUrl { .. }));
+    }
+}
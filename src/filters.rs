@@ -20,3 +20,26 @@ pub(crate) fn humanize_size(val: &Value, _args: &HashMap<String, Value>) -> tera
         )))
     }
 }
+
+/// render some markdown into html, removing anything unsafe (scripts, event handlers…)
+/// from the result. The output still needs the `safe` filter to not be escaped again.
+pub(crate) fn markdown(val: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let raw = match val.as_str() {
+        Some(s) => s,
+        None => {
+            return Err(tera::Error::msg(format!(
+                "Invalid value, expected a string but got {:?}",
+                val
+            )))
+        }
+    };
+
+    let parser = pulldown_cmark::Parser::new_ext(
+        raw,
+        pulldown_cmark::Options::ENABLE_TABLES | pulldown_cmark::Options::ENABLE_STRIKETHROUGH,
+    );
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, parser);
+
+    Ok(ammonia::clean(&html).into())
+}
@@ -0,0 +1,55 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::state::AppState;
+
+/// the hashed urls change with the content, so they never need to be fetched again
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+pub(crate) async fn get_static_file(
+    Path(path): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    let assets = state.static_assets.read();
+    let Some((file, hashed)) = assets.get(&path) else {
+        return (StatusCode::NOT_FOUND, "not found").into_response();
+    };
+
+    let etag = format!("\"{}\"", file.hash);
+    // the plain urls (or an outdated hash) must be checked every time
+    let cache_control = if state.dev {
+        "no-store"
+    } else if hashed {
+        IMMUTABLE
+    } else {
+        "no-cache"
+    };
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.split(',').any(|tag| tag.trim() == etag));
+    if not_modified {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [
+                (header::ETAG, etag),
+                (header::CACHE_CONTROL, cache_control.to_string()),
+            ],
+        )
+            .into_response();
+    }
+
+    (
+        [
+            (header::CONTENT_TYPE, file.mime_type.clone()),
+            (header::ETAG, etag),
+            (header::CACHE_CONTROL, cache_control.to_string()),
+        ],
+        file.content.clone(),
+    )
+        .into_response()
+}
@@ -2,17 +2,35 @@ use axum::{
     body::StreamBody,
     extract::{Path, Query, State},
     http::{header, HeaderMap, StatusCode},
-    response::{IntoResponse, Response},
+    response::{Html, IntoResponse, Response},
 };
+use tokio::io::AsyncReadExt;
 use tokio_util::io::ReaderStream;
 
-use crate::{error::Result, state::AppState};
+use crate::{
+    auth::Admin, db::DbFile, error::Result, metrics::count_download, preview, state::AppState,
+    strip::ORIGINAL_KIND, thumbnail::DerivedKind,
+};
 
 #[derive(serde::Deserialize, Debug)]
 pub(crate) struct Params {
     dl: Option<bool>,
 }
 
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ViewMode {
+    /// highlighted text with line numbers
+    Source,
+    /// rendered as html, only for markdown files
+    Markdown,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub(crate) struct ViewParams {
+    mode: Option<ViewMode>,
+}
+
 pub(crate) async fn get_file(
     Path((tok_path, file_id)): Path<(String, i64)>,
     state: State<AppState>,
@@ -23,10 +41,204 @@ pub(crate) async fn get_file(
         Some(file) => file,
     };
 
+    serve_file(state, file, params.dl.unwrap_or(false)).await
+}
+
+/// A file uploaded to an inbox, only visible to the admins
+pub(crate) async fn get_batch_file(
+    Path((tok_path, file_id)): Path<(String, i64)>,
+    state: State<AppState>,
+    _admin: Admin,
+    params: Query<Params>,
+) -> Result<Response> {
+    let file = match state.db.get_valid_batch_file(&tok_path, file_id).await? {
+        None => return Ok((StatusCode::NOT_FOUND, "not found").into_response()),
+        Some(file) => file,
+    };
+
+    serve_file(state, file, params.dl.unwrap_or(false)).await
+}
+
+/// The file as it was uploaded, before its metadata were removed
+pub(crate) async fn get_original(
+    Path((tok_path, file_id)): Path<(String, i64)>,
+    state: State<AppState>,
+    _admin: Admin,
+) -> Result<Response> {
+    let file = match state.db.get_valid_file(&tok_path, file_id).await? {
+        Some(file) => file,
+        None => match state.db.get_valid_batch_file(&tok_path, file_id).await? {
+            Some(file) => file,
+            None => return Ok((StatusCode::NOT_FOUND, "not found").into_response()),
+        },
+    };
+
+    match state.db.get_derived_file(file.id, ORIGINAL_KIND).await? {
+        Some(original) => {
+            let file_name = match file.name {
+                Some(n) => n,
+                None => format!("{:04}_{:04}", file.token_id, file.id),
+            };
+            serve_blob(
+                state,
+                original.backend_type,
+                original.backend_data,
+                original.mime_type,
+                file_name,
+                true,
+            )
+            .await
+        }
+        None => Ok((StatusCode::NOT_FOUND, "not found").into_response()),
+    }
+}
+
+/// Page showing the beginning of a text file, highlighted
+pub(crate) async fn get_text_preview(
+    Path((tok_path, file_id)): Path<(String, i64)>,
+    state: State<AppState>,
+    params: Query<ViewParams>,
+) -> Result<Response> {
+    let file = match state.db.get_valid_file(&tok_path, file_id).await? {
+        Some(file) if preview::is_textual(file.mime_type.as_deref(), file.name.as_deref()) => file,
+        _ => return Ok((StatusCode::NOT_FOUND, "not found").into_response()),
+    };
+
+    let blob = state
+        .get_blob(file.backend_type.as_str(), file.backend_data.clone())
+        .await?;
+    let mut raw = Vec::new();
+    blob.take(preview::MAX_PREVIEW_BYTES as u64 + 1)
+        .read_to_end(&mut raw)
+        .await?;
+    let truncated = raw.len() > preview::MAX_PREVIEW_BYTES;
+    raw.truncate(preview::MAX_PREVIEW_BYTES);
+
+    let is_markdown = preview::is_markdown(file.mime_type.as_deref(), file.name.as_deref());
+    let mode = match params.mode {
+        Some(ViewMode::Markdown) if is_markdown => ViewMode::Markdown,
+        None if is_markdown => ViewMode::Markdown,
+        _ => ViewMode::Source,
+    };
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("tok_path", &tok_path);
+    ctx.insert("file_id", &file.id);
+    ctx.insert("file_name", &file.name);
+    ctx.insert("truncated", &truncated);
+    ctx.insert("max_preview_size", &preview::MAX_PREVIEW_BYTES);
+    ctx.insert("is_markdown", &is_markdown);
+    ctx.insert("markdown_mode", &(mode == ViewMode::Markdown));
+
+    match preview::decode_text(&raw) {
+        None => ctx.insert("binary", &true),
+        Some(text) if mode == ViewMode::Markdown => ctx.insert("text", &text),
+        Some(text) => {
+            let highlighted = tokio::task::spawn_blocking(move || {
+                preview::highlight(&text, file.mime_type.as_deref(), file.name.as_deref())
+            })
+            .await
+            .map_err(|err| std::io::Error::other(format!("{err:?}")))??;
+            ctx.insert("syntax", &highlighted.syntax);
+            ctx.insert("lines", &highlighted.lines);
+            ctx.insert("background", &highlighted.background);
+        }
+    }
+
+    let html: Html<String> = state
+        .templates
+        .read()
+        .render("text_preview.html", &ctx)?
+        .into();
+    Ok(html.into_response())
+}
+
+/// Small version of an uploaded image or pdf, for listings
+pub(crate) async fn get_thumbnail(
+    Path((tok_path, file_id)): Path<(String, i64)>,
+    state: State<AppState>,
+) -> Result<Response> {
+    get_derived(state, &tok_path, file_id, DerivedKind::Thumbnail).await
+}
+
+/// Lighter version of an uploaded image or pdf, to display in a page
+pub(crate) async fn get_preview(
+    Path((tok_path, file_id)): Path<(String, i64)>,
+    state: State<AppState>,
+) -> Result<Response> {
+    get_derived(state, &tok_path, file_id, DerivedKind::Preview).await
+}
+
+/// Serve the generated version of a file. They're created in the background
+/// so they may not be there yet, in which case images are served as is.
+async fn get_derived(
+    state: State<AppState>,
+    tok_path: &str,
+    file_id: i64,
+    kind: DerivedKind,
+) -> Result<Response> {
+    let file = match state.db.get_valid_file(tok_path, file_id).await? {
+        None => return Ok((StatusCode::NOT_FOUND, "not found").into_response()),
+        Some(file) => file,
+    };
+
+    match state.db.get_derived_file(file.id, kind.as_str()).await? {
+        Some(derived) => {
+            let file_name = format!("{:04}_{:04}_{}.jpg", file.token_id, file.id, kind.as_str());
+            serve_blob(
+                state,
+                derived.backend_type,
+                derived.backend_data,
+                derived.mime_type,
+                file_name,
+                false,
+            )
+            .await
+        }
+        None if file
+            .mime_type
+            .as_deref()
+            .is_some_and(|m| m.starts_with("image/")) =>
+        {
+            serve_file(state, file, false).await
+        }
+        None => Ok((StatusCode::NOT_FOUND, "not found").into_response()),
+    }
+}
+
+async fn serve_file(state: State<AppState>, file: DbFile, download: bool) -> Result<Response> {
+    tracing::info!(
+        token_id = file.token_id,
+        file_id = file.id,
+        backend = %file.backend_type,
+        "Serving file"
+    );
+    let file_name = match file.name {
+        Some(n) => n,
+        None => format!("{:04}_{:04}", file.token_id, file.id),
+    };
+
+    serve_blob(
+        state,
+        file.backend_type,
+        file.backend_data,
+        file.mime_type,
+        file_name,
+        download,
+    )
+    .await
+}
+
+async fn serve_blob(
+    state: State<AppState>,
+    backend_type: String,
+    backend_data: String,
+    mime_type: Option<String>,
+    file_name: String,
+    download: bool,
+) -> Result<Response> {
     let mut headers = HeaderMap::new();
-    let mime_type = file
-        .mime_type
-        .unwrap_or_else(|| "application/octet-stream".to_string());
+    let mime_type = mime_type.unwrap_or_else(|| "application/octet-stream".to_string());
 
     headers.insert(
         header::CONTENT_TYPE,
@@ -35,15 +247,7 @@ pub(crate) async fn get_file(
             .unwrap_or_else(|_| "application/octet-stream".parse().unwrap()),
     );
 
-    let file_name = match file.name {
-        Some(n) => n,
-        None => format!("{:04}_{:04}", file.token_id, file.id),
-    };
-    let content_disp_type = if params.dl.unwrap_or(false) {
-        "attachment"
-    } else {
-        "inline"
-    };
+    let content_disp_type = if download { "attachment" } else { "inline" };
 
     headers.insert(
         header::CONTENT_DISPOSITION,
@@ -52,14 +256,9 @@ pub(crate) async fn get_file(
             .unwrap(),
     );
 
-    tracing::debug!(
-        "{} reading backend data {}",
-        file.backend_type,
-        file.backend_data
-    );
-    let blob = state
-        .get_blob(file.backend_type.as_str(), file.backend_data)
-        .await?;
+    tracing::debug!(backend = %backend_type, data = %backend_data, "Reading blob");
+    let blob = state.get_blob(backend_type.as_str(), backend_data).await?;
+    let blob = count_download(&backend_type, blob);
 
     // stream an AsyncRead as a response
     // https://github.com/tokio-rs/axum/discussions/608
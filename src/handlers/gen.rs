@@ -11,18 +11,28 @@ use time::OffsetDateTime;
 use crate::auth::Admin;
 use crate::error::Result;
 use crate::handlers::flash_utils::NotifLevel;
+use crate::slug::{random_slug, SlugKind};
 use crate::state::AppState;
 use crate::upload::StorageBackend;
 
 use super::flash_utils::Notif;
 
+/// how many times to try generating a path before giving up
+const MAX_SLUG_ATTEMPTS: usize = 5;
+
 // need the serialize_with bits to ensure we serialize into a string.
 // because a browser will send these fields as string, this ensure consistent
 // serialization. There may be a way to accept both an integer and a string, but
 // I don't know how.
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct GenTokenForm {
+    /// the path of the link, or a prefix for it when generated
+    #[serde(default)]
     pub path: String,
+
+    #[serde(rename = "path-kind", default)]
+    pub path_kind: PathKind,
+
     #[serde(
         rename = "max-size-mib",
         deserialize_with = "deserialize_sentinel",
@@ -42,6 +52,71 @@ pub struct GenTokenForm {
 
     #[serde(rename = "storage-backend")]
     pub storage_backend: StorageBackendType,
+
+    /// comma separated list of mime types, mime prefixes (image/*) or extensions (.pdf)
+    #[serde(rename = "allowed-types", default)]
+    pub allowed_types: Option<String>,
+
+    #[serde(
+        rename = "max-files",
+        deserialize_with = "deserialize_sentinel",
+        serialize_with = "serialize_opt_str",
+        default
+    )]
+    pub max_files: Option<i64>,
+
+    #[serde(
+        rename = "max-file-size-mib",
+        deserialize_with = "deserialize_sentinel",
+        serialize_with = "serialize_opt_str",
+        default
+    )]
+    pub max_file_size_mib: Option<i64>,
+
+    /// accept several uploads until the link expires instead of a single one
+    #[serde(
+        rename = "multi-use",
+        deserialize_with = "deserialize_checkbox",
+        default
+    )]
+    pub multi_use: bool,
+
+    #[serde(default)]
+    pub title: Option<String>,
+
+    /// markdown, rendered on the upload form and the file list
+    #[serde(default)]
+    pub description: Option<String>,
+
+    /// remove the location and device information from uploaded pictures
+    #[serde(
+        rename = "strip-metadata",
+        deserialize_with = "deserialize_checkbox",
+        default
+    )]
+    pub strip_metadata: bool,
+
+    /// keep the unmodified pictures, only visible to the admins
+    #[serde(
+        rename = "keep-original",
+        deserialize_with = "deserialize_checkbox",
+        default
+    )]
+    pub keep_original: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub enum PathKind {
+    /// use the given path as is
+    #[default]
+    #[serde(rename = "manual")]
+    Manual,
+    /// generate a random path, using the given path as a prefix
+    #[serde(rename = "random")]
+    Random,
+    /// generate a path made of words, using the given path as a prefix
+    #[serde(rename = "words")]
+    Words,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -68,6 +143,7 @@ pub(crate) async fn get_token(
     }
 
     ctx.insert("notifications", &notifications);
+    ctx.insert("strip_metadata_default", &state.strip_metadata_default);
 
     Ok((
         flashes,
@@ -110,15 +186,87 @@ pub(crate) async fn create_token(
         StorageBackendType::Garage => state.garage.get_type(),
     };
 
-    let ct = crate::db::CreateToken {
-        path: &form.path,
-        max_size_mib: form.max_size_mib,
-        valid_until,
-        content_expires_after_hours: form.content_expires_after_hours,
-        backend_type,
+    let allowed_types = form
+        .allowed_types
+        .as_deref()
+        .map(str::trim)
+        .filter(|t| !t.is_empty());
+    let title = form
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|t| !t.is_empty());
+    let description = form
+        .description
+        .as_deref()
+        .map(str::trim)
+        .filter(|t| !t.is_empty());
+
+    let limits = [form.max_size_mib, form.max_files, form.max_file_size_mib];
+    if limits.into_iter().flatten().any(|limit| limit < 1) {
+        let flash = flash.error("The size and file count limits must be at least 1.");
+        return Ok((flash, Redirect::to("/gen").into_response()));
+    }
+
+    let slug_kind = match form.path_kind {
+        PathKind::Manual => None,
+        PathKind::Random => Some(SlugKind::Random),
+        PathKind::Words => Some(SlugKind::Words),
     };
 
-    let r = state.db.create_token(ct).await?;
+    let r = match slug_kind {
+        None if form.path.trim().is_empty() => {
+            let flash = flash.error("A path is required.");
+            return Ok((flash, Redirect::to("/gen").into_response()));
+        }
+        None => {
+            let ct = crate::db::CreateToken {
+                path: &form.path,
+                max_size_mib: form.max_size_mib,
+                valid_until,
+                content_expires_after_hours: form.content_expires_after_hours,
+                backend_type,
+                allowed_types,
+                max_files: form.max_files,
+                max_file_size_mib: form.max_file_size_mib,
+                multi_use: form.multi_use,
+                title,
+                description,
+                strip_metadata: form.strip_metadata,
+                keep_original: form.strip_metadata && form.keep_original,
+            };
+            state.db.create_token(ct).await?
+        }
+        Some(kind) => {
+            // collisions are astronomically unlikely, but a prefix-only form resubmitted
+            // shouldn't fail because of bad luck, so retry a few times
+            let mut r = Err(crate::db::TokenError::AlreadyExist);
+            for _ in 0..MAX_SLUG_ATTEMPTS {
+                let path = random_slug(kind, Some(&form.path));
+                let ct = crate::db::CreateToken {
+                    path: &path,
+                    max_size_mib: form.max_size_mib,
+                    valid_until,
+                    content_expires_after_hours: form.content_expires_after_hours,
+                    backend_type,
+                    allowed_types,
+                    max_files: form.max_files,
+                    max_file_size_mib: form.max_file_size_mib,
+                    multi_use: form.multi_use,
+                    title,
+                    description,
+                    strip_metadata: form.strip_metadata,
+                    keep_original: form.strip_metadata && form.keep_original,
+                };
+                r = state.db.create_token(ct).await?;
+                if r.is_ok() {
+                    break;
+                }
+                tracing::warn!("Generated path {path} already taken, retrying");
+            }
+            r
+        }
+    };
 
     match r {
         Err(crate::db::TokenError::AlreadyExist) => {
@@ -143,10 +291,17 @@ pub(crate) async fn create_token(
                 (StatusCode::CONFLICT, page).into_response(),
             ))
         }
-        Ok(tok) => Ok((
-            flash.success("Token created."),
-            Redirect::to(&format!("/f/{}", urlencoding::encode(&tok.path))).into_response(),
-        )),
+        Ok(tok) => {
+            let path = urlencoding::encode(&tok.path);
+            let flash = if tok.multi_use {
+                flash.success(format!(
+                    "Inbox created. The uploads can be seen at /f/{path}/batches"
+                ))
+            } else {
+                flash.success("Token created.")
+            };
+            Ok((flash, Redirect::to(&format!("/f/{path}")).into_response()))
+        }
     }
 }
 
@@ -162,7 +317,8 @@ where
     match value {
         Ok(Maybe::Just(x)) => Ok(x),
         Ok(Maybe::Nothing(raw)) => {
-            if raw == "None" {
+            // empty string is what browsers send for an empty number input
+            if raw == "None" || raw.is_empty() {
                 Ok(None)
             } else {
                 Err(serde::de::Error::custom(format!(
@@ -178,6 +334,23 @@ where
     }
 }
 
+// an html checkbox is only sent when checked, with the value "on" by default.
+// Also accept booleans for the cli which serializes the form.
+fn deserialize_checkbox<'de, D>(deserializer: D) -> std::result::Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: Option<String> = Deserialize::deserialize(deserializer)?;
+    match value.as_deref() {
+        None | Some("") | Some("false") | Some("off") => Ok(false),
+        Some("on") | Some("true") => Ok(true),
+        Some(raw) => Err(serde::de::Error::custom(format!(
+            "Unexpected checkbox value {}",
+            raw
+        ))),
+    }
+}
+
 // serde(untagged) and serde(flatten) are buggy with serde_qs and serde_urlencoded
 // there is a workaround:
 // https://github.com/nox/serde_urlencoded/issues/33
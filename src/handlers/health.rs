@@ -0,0 +1,91 @@
+use std::{collections::BTreeMap, future::Future, time::Duration};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tokio::time::Instant;
+
+use crate::{error::Result, state::AppState, upload::StorageBackend};
+
+/// a dependency slower than that is as good as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(serde::Serialize, Debug)]
+struct Check {
+    ok: bool,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+/// The process is up and serving requests, nothing else is checked.
+pub(crate) async fn get_healthz() -> &'static str {
+    "ok"
+}
+
+/// Whether the database and all the storage backends can be used.
+pub(crate) async fn get_readyz(State(state): State<AppState>) -> Response {
+    let (database, local_fs, garage) = tokio::join!(
+        run_check(state.db.ping()),
+        run_check(state.storage_fs.check()),
+        run_check(state.garage.check()),
+    );
+    let checks = BTreeMap::from([
+        ("database", database),
+        (state.storage_fs.get_type(), local_fs),
+        (state.garage.get_type(), garage),
+    ]);
+
+    let ready = checks.values().all(|c| c.ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(Readiness { ready, checks })).into_response()
+}
+
+async fn run_check<F>(check: F) -> Check
+where
+    F: Future<Output = Result<()>>,
+{
+    let start = Instant::now();
+    let error = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(error_chain(&err)),
+        Err(_) => Some(format!("no answer after {}s", CHECK_TIMEOUT.as_secs())),
+    };
+    if let Some(error) = &error {
+        tracing::warn!("Readiness check failed: {error}");
+    }
+    Check {
+        ok: error.is_none(),
+        duration_ms: start.elapsed().as_millis(),
+        error,
+    }
+}
+
+/// The error and its causes, the top one alone is often too vague. Some errors
+/// already include their source in their message, it's not repeated then.
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        let cause = err.to_string();
+        if !message.contains(&cause) {
+            message.push_str(": ");
+            message.push_str(&cause);
+        }
+        source = err.source();
+    }
+    message
+}
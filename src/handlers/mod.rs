@@ -1,4 +1,7 @@
+pub(crate) mod assets;
 pub(crate) mod file;
 pub(crate) mod flash_utils;
 pub mod gen;
+pub(crate) mod health;
+pub(crate) mod metrics;
 pub(crate) mod upload;
@@ -1,5 +1,6 @@
+use async_compression::futures::write::GzipEncoder;
 use async_zip::error::ZipError;
-use async_zip::{Compression, ZipEntryBuilder};
+use async_zip::{Compression, ZipDateTime, ZipEntryBuilder};
 use futures::{Future, FutureExt};
 use hyper::{header, HeaderMap};
 use std::io::ErrorKind;
@@ -7,28 +8,39 @@ use std::pin::Pin;
 use std::str::FromStr;
 use std::task::{Context, Poll};
 
-use axum::extract::{Multipart, Path, Query};
+use axum::body::Bytes;
+use axum::extract::multipart::{Field, MultipartError};
+use axum::extract::BodyStream;
+use axum::extract::{Form, Multipart, Path, Query, RawQuery};
 use axum::response::{Redirect, Response};
 use axum::{extract::State, response::Html, response::IntoResponse};
-use axum_flash::IncomingFlashes;
+use axum_flash::{Flash, IncomingFlashes};
 use humantime::format_duration;
 use serde::{de, Deserialize};
 use time::{Duration, OffsetDateTime};
 use tracing::Instrument;
 
-use futures::TryStreamExt;
-use tokio::io::{AsyncWrite, DuplexStream};
+use futures::{AsyncWriteExt, StreamExt, TryStreamExt};
+use tokio::io::{AsyncReadExt, AsyncWrite, DuplexStream};
 use tokio_util::compat::{
     Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt,
 };
 
 use pin_project::pin_project;
 
-use crate::db::{DbFile, DbFileMetadata, DbToken, GetTokenResult};
+use crate::auth::Admin;
+use crate::db::{DbFile, DbFileMetadata, DbToken, GetTokenResult, UploadToken, UploaderInfo};
 use crate::error::{AppError, Result};
+use crate::fetch::{self, FetchError};
 use crate::handlers::flash_utils::ctx_from_flashes;
+use crate::metrics::{count_download, metrics, ActiveUpload};
+use crate::preview;
 use crate::state::AppState;
+use crate::strip::{self, MetadataStripper};
+use crate::tar::{self, TarEntry};
+use crate::thumbnail::{self, DerivedKind};
 use crate::upload::{InitFile, StorageBackend};
+use crate::zip::{self, ZipEntry, ZipWriter};
 
 // wrapper because I later need a futures::AsyncWrite, but tokio's File implements
 // tokio::io::AsyncWrite so this bridges the two.
@@ -64,10 +76,19 @@ struct TplFile {
     mime_prefix: Option<String>,
     name: Option<String>,
     size: Option<i64>,
+    /// a thumbnail has been generated for this file
+    thumbnail: bool,
+    /// when the picture was taken, if known
+    captured_at: Option<String>,
+    /// the unmodified upload was kept when removing its metadata
+    original: bool,
+    /// can be displayed as text in a preview page
+    textual: bool,
 }
 
 impl std::convert::From<(DbFile, DbFileMetadata)> for TplFile {
     fn from((f, m): (DbFile, DbFileMetadata)) -> Self {
+        let textual = preview::is_textual(f.mime_type.as_deref(), f.name.as_deref());
         Self {
             id: f.id,
             mime_type: f.mime_type.clone(),
@@ -77,6 +98,13 @@ impl std::convert::From<(DbFile, DbFileMetadata)> for TplFile {
             }),
             name: f.name,
             size: m.size_b,
+            thumbnail: false,
+            original: false,
+            textual,
+            captured_at: m.captured_at.map(|d| {
+                let fmt = time::macros::format_description!("[year]/[month]/[day] [hour]:[minute]");
+                d.format(&fmt).expect("formatting offsetdatetime")
+            }),
         }
     }
 }
@@ -87,6 +115,7 @@ pub(crate) async fn get_upload_form(
     incoming_flashes: IncomingFlashes,
     Path(tok_path): Path<String>,
     Query(file_query): Query<FileQuery>,
+    RawQuery(raw_query): RawQuery,
 ) -> Result<Response> {
     let tok_path =
         urlencoding::decode(&tok_path).map_err(|e| crate::error::AppError::InvalidUrlToken {
@@ -105,12 +134,38 @@ pub(crate) async fn get_upload_form(
             Ok((incoming_flashes, rsp).into_response())
         }
         GetTokenResult::Fresh(tok) => upload_form(state, incoming_flashes, tok).await,
+        // uploads to an inbox are only visible to the admins, through the batches view
+        GetTokenResult::Used(tok) if tok.multi_use => {
+            let html: Html<String> = state
+                .templates
+                .read()
+                .render("no_link_found.html", &tera::Context::new())?
+                .into();
+            let rsp = (hyper::StatusCode::NOT_FOUND, html);
+            Ok((incoming_flashes, rsp).into_response())
+        }
         GetTokenResult::Used(tok) => {
-            let span = tracing::info_span!("token {}-{}", tok.id, tok.path);
-            if file_query.zip {
-                get_files_zip(state, incoming_flashes, tok)
-                    .instrument(span)
-                    .await
+            let span = tracing::info_span!("token", token_id = tok.id, token_path = %tok.path);
+            if file_query.zip || file_query.tar || file_query.tar_gz {
+                let files = match archive_files(&state, &tok, raw_query.as_deref()).await? {
+                    Ok(files) => files,
+                    Err(msg) => {
+                        return Ok((hyper::StatusCode::BAD_REQUEST, msg).into_response());
+                    }
+                };
+                if file_query.zip && file_query.stored {
+                    get_files_zip_stored(state, incoming_flashes, tok, files)
+                        .instrument(span)
+                        .await
+                } else if file_query.zip {
+                    get_files_zip(state, incoming_flashes, tok, files)
+                        .instrument(span)
+                        .await
+                } else {
+                    get_files_tar(state, incoming_flashes, tok, files, file_query.tar_gz)
+                        .instrument(span)
+                        .await
+                }
             } else {
                 get_files_html(state, incoming_flashes, tok)
                     .instrument(span)
@@ -123,6 +178,7 @@ pub(crate) async fn get_upload_form(
 pub(crate) async fn post_upload_form(
     Path(tok_path): Path<String>,
     state: State<AppState>,
+    flash: Flash,
     mut multipart: Multipart,
 ) -> Result<Response> {
     // TODO: maybe make a custom extractor for the token which handles the
@@ -144,23 +200,39 @@ pub(crate) async fn post_upload_form(
         }
     };
 
-    let backend: Box<dyn StorageBackend + Send + Sync> =
-        if token.backend_type == state.storage_fs.get_type() {
-            Box::new(state.storage_fs.clone())
-        } else if token.backend_type == state.garage.get_type() {
-            Box::new(state.garage.clone())
-        } else {
-            return Err(crate::error::AppError::UnknownStorageBackend(
-                token.backend_type,
-            ));
-        };
+    let multi_use = token.multi_use;
+    let mut session = UploadSession::start(&state, token).await?;
 
-    let token = state.db.initiate_upload(token).await?;
+    let mut uploader = UploaderInfo::default();
+    let mut paste_name = None;
+    let mut paste_language = None;
+    let mut file_ids = Vec::new();
+    let mut pasted_id = None;
+    while let Some(mut field) = multipart.next_field().await? {
+        let text_field = match field.name() {
+            Some(UPLOADER_NAME_FIELD) => Some((&mut uploader.name, "name", MAX_NAME_LEN)),
+            Some(UPLOADER_EMAIL_FIELD) => Some((&mut uploader.email, "email", MAX_EMAIL_LEN)),
+            Some(MESSAGE_FIELD) => Some((&mut uploader.message, "message", MAX_MESSAGE_LEN)),
+            Some(PASTE_NAME_FIELD) => Some((&mut paste_name, "paste name", MAX_PASTE_NAME_LEN)),
+            Some(PASTE_LANGUAGE_FIELD) => {
+                Some((&mut paste_language, "language", MAX_PASTE_NAME_LEN))
+            }
+            _ => None,
+        };
+        if let Some((dest, label, max_len)) = text_field {
+            match read_text_field(field, label, max_len).await? {
+                Ok(value) => *dest = value,
+                Err(rejection) => return Ok(reject_upload(flash, &tok_path, rejection)),
+            }
+            if let Some(email) = uploader.email.as_deref().filter(|e| !is_valid_email(e)) {
+                let rejection = UploadRejection::InvalidEmail {
+                    email: email.to_string(),
+                };
+                return Ok(reject_upload(flash, &tok_path, rejection));
+            }
+            continue;
+        }
 
-    let mut total_bytes = 0;
-    let mut file_idx = 0;
-    while let Some(field) = multipart.next_field().await? {
-        file_idx += 1;
         tracing::info!(
             "got a new field here {:?} of type {:?} for file {:?}",
             field.name(),
@@ -168,15 +240,401 @@ pub(crate) async fn post_upload_form(
             field.file_name(),
         );
 
-        let mime_type = field.content_type();
+        let stored = if field.name() == Some(PASTE_FIELD) {
+            // the textarea is always sent, even when left empty, in which case it
+            // shouldn't count against the restrictions of the token.
+            let mut first_chunk = None;
+            while let Some(chunk) = field.chunk().await? {
+                if !chunk.is_empty() {
+                    first_chunk = Some(chunk);
+                    break;
+                }
+            }
+            let Some(first_chunk) = first_chunk else {
+                continue;
+            };
+            let name = paste_file_name(paste_name.as_deref(), paste_language.as_deref());
+            let body = futures::stream::once(futures::future::ready(Ok(first_chunk)))
+                .chain(field.map_err(multipart_io_error));
+            let stored = session
+                .store_file(Some(PASTE_MIME_TYPE), Some(&name), body)
+                .await?;
+            if let Ok(Some(id)) = stored {
+                pasted_id = Some(id);
+            }
+            stored
+        } else {
+            let mime_type = field.content_type().map(str::to_string);
+            let file_name = field.file_name().map(str::to_string);
+            session
+                .store_file(
+                    mime_type.as_deref(),
+                    file_name.as_deref(),
+                    field.map_err(multipart_io_error),
+                )
+                .await?
+        };
+
+        match stored {
+            Ok(Some(id)) => file_ids.push(id),
+            Ok(None) => (),
+            Err(rejection) => return Ok(reject_upload(flash, &tok_path, rejection)),
+        }
+    }
+
+    if session.finish(uploader).await? {
+        if multi_use {
+            let redirect = Redirect::to(&format!("/f/{}", tok_path));
+            let flash = flash.success("Thank you, your files have been received.");
+            return Ok((flash, redirect).into_response());
+        }
+        // a lone snippet is better displayed on its own
+        if let (Some(id), 1) = (pasted_id, file_ids.len()) {
+            return Ok(Redirect::to(&format!("/f/{}/{}/view", tok_path, id)).into_response());
+        }
+    }
+
+    // TODO: maybe use https://docs.rs/axum/0.6.0-rc.4/axum/extract/struct.OriginalUri.html
+    // instead of reconstructing the path here
+    Ok(Redirect::to(&format!("/f/{}", tok_path)).into_response())
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub(crate) struct PasteQuery {
+    name: Option<String>,
+    lang: Option<String>,
+}
+
+/// Raw text sent as the body of the request, for `curl --data-binary @some.log`.
+/// Responds with the url of the snippet.
+pub(crate) async fn post_paste(
+    Path(tok_path): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<PasteQuery>,
+    body: BodyStream,
+) -> Result<Response> {
+    let tok_path =
+        urlencoding::decode(&tok_path).map_err(|e| crate::error::AppError::InvalidUrlToken {
+            token: tok_path.clone(),
+            source: e,
+        })?;
+
+    let token = match state.db.get_valid_token(&tok_path).await? {
+        GetTokenResult::Fresh(t) => t,
+        GetTokenResult::NotFound | GetTokenResult::Used(_) => {
+            return Ok(
+                (hyper::StatusCode::NOT_FOUND, "No valid link found here.\n").into_response(),
+            );
+        }
+    };
+
+    let multi_use = token.multi_use;
+    let mut session = UploadSession::start(&state, token).await?;
+    let name = paste_file_name(query.name.as_deref(), query.lang.as_deref());
+    let body = body.map_err(|err| std::io::Error::other(format!("{err:?}")));
+    let file_id = match session
+        .store_file(Some(PASTE_MIME_TYPE), Some(&name), body)
+        .await?
+    {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            return Ok((hyper::StatusCode::BAD_REQUEST, "Nothing to paste.\n").into_response());
+        }
+        Err(rejection) => return Ok(reject_raw_upload(rejection)),
+    };
+    session.finish(UploaderInfo::default()).await?;
+
+    if multi_use {
+        Ok("Thank you, your text has been received.\n".into_response())
+    } else {
+        Ok(format!("{}/f/{}/{}/view\n", state.base_url, tok_path, file_id).into_response())
+    }
+}
+
+/// What is sent back after a raw upload, when asking for json
+#[derive(serde::Serialize, Debug)]
+struct RawUploadResponse {
+    id: i64,
+    name: String,
+    mime_type: Option<String>,
+    /// files sent to an inbox are only visible to the admins
+    url: Option<String>,
+}
+
+/// The body of the request is the file, for `curl -T some.tar.gz https://vrac/f/xyz/raw/`.
+/// Under `raw/` so that files named like `paste` or `batches` aren't routed elsewhere.
+/// Responds with the url of the file, as text or as json depending on the `Accept` header.
+pub(crate) async fn put_raw_file(
+    Path((tok_path, file_name)): Path<(String, String)>,
+    state: State<AppState>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Result<Response> {
+    let tok_path =
+        urlencoding::decode(&tok_path).map_err(|e| crate::error::AppError::InvalidUrlToken {
+            token: tok_path.clone(),
+            source: e,
+        })?;
+
+    let token = match state.db.get_valid_token(&tok_path).await? {
+        GetTokenResult::Fresh(t) => t,
+        GetTokenResult::NotFound | GetTokenResult::Used(_) => {
+            return Ok(
+                (hyper::StatusCode::NOT_FOUND, "No valid link found here.\n").into_response(),
+            );
+        }
+    };
+
+    let file_name = file_name.trim().replace(['/', '\\'], "_");
+    let mime_type = raw_upload_mime_type(&headers, &file_name);
+    let multi_use = token.multi_use;
+    let mut session = UploadSession::start(&state, token).await?;
+    let body = body.map_err(|err| std::io::Error::other(format!("{err:?}")));
+    let file_id = match session
+        .store_file(mime_type.as_deref(), Some(&file_name), body)
+        .await?
+    {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            return Ok((hyper::StatusCode::BAD_REQUEST, "The file is empty.\n").into_response());
+        }
+        Err(rejection) => return Ok(reject_raw_upload(rejection)),
+    };
+    session.finish(UploaderInfo::default()).await?;
+
+    let response = RawUploadResponse {
+        id: file_id,
+        name: file_name,
+        mime_type,
+        url: (!multi_use).then(|| format!("{}/f/{}/{}", state.base_url, tok_path, file_id)),
+    };
+    Ok(raw_upload_response(&headers, response))
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub(crate) struct FetchForm {
+    url: String,
+    /// otherwise taken from the response or the url
+    name: Option<String>,
+}
+
+/// Mirror a remote file, streamed directly to the storage backend.
+pub(crate) async fn post_fetch(
+    Path(tok_path): Path<String>,
+    state: State<AppState>,
+    _admin: Admin,
+    headers: HeaderMap,
+    Form(form): Form<FetchForm>,
+) -> Result<Response> {
+    let tok_path =
+        urlencoding::decode(&tok_path).map_err(|e| crate::error::AppError::InvalidUrlToken {
+            token: tok_path.clone(),
+            source: e,
+        })?;
+
+    let token = match state.db.get_valid_token(&tok_path).await? {
+        GetTokenResult::Fresh(t) => t,
+        GetTokenResult::NotFound | GetTokenResult::Used(_) => {
+            return Ok(
+                (hyper::StatusCode::NOT_FOUND, "No valid link found here.\n").into_response(),
+            );
+        }
+    };
+
+    let remote = match fetch::fetch(&state.http_client, form.url.trim()).await {
+        Ok(remote) => remote,
+        Err(err) => {
+            tracing::info!("Cannot fetch remote file: {err}");
+            let status = match err {
+                FetchError::InvalidUrl { .. } => hyper::StatusCode::BAD_REQUEST,
+                _ => hyper::StatusCode::BAD_GATEWAY,
+            };
+            return Ok((status, format!("{err}\n")).into_response());
+        }
+    };
+
+    let file_name = form
+        .name
+        .map(|n| n.trim().replace(['/', '\\'], "_"))
+        .filter(|n| !n.is_empty())
+        .unwrap_or(remote.file_name);
+    let mime_type = remote.mime_type.or_else(|| {
+        mime_guess::from_path(&file_name)
+            .first()
+            .map(|m| m.essence_str().to_string())
+    });
+    tracing::info!(token_id = token.id, url = %remote.url, "Fetching remote file");
+
+    let multi_use = token.multi_use;
+    let mut session = UploadSession::start(&state, token).await?;
+    session
+        .restrictions
+        .max_size_mib
+        .get_or_insert(state.fetch_max_size_mib);
+    let body = fetch::body_stream(remote.body, fetch::BODY_IDLE_TIMEOUT);
+    let file_id = match session
+        .store_file(mime_type.as_deref(), Some(&file_name), body)
+        .await
+    {
+        Ok(Ok(Some(id))) => id,
+        Ok(Ok(None)) => {
+            return Ok((
+                hyper::StatusCode::BAD_GATEWAY,
+                "The remote file is empty.\n",
+            )
+                .into_response());
+        }
+        Ok(Err(rejection)) => return Ok(reject_raw_upload(rejection)),
+        Err(AppError::UploadError(err)) if err.kind() == ErrorKind::TimedOut => {
+            tracing::info!(url = %remote.url, "Remote file stalled: {err}");
+            let msg = format!("Cannot fetch {}, {err}\n", remote.url);
+            return Ok((hyper::StatusCode::GATEWAY_TIMEOUT, msg).into_response());
+        }
+        Err(err) => return Err(err),
+    };
+    state
+        .db
+        .set_file_source(file_id, &remote.url, remote.etag.as_deref())
+        .await?;
+    session.finish(UploaderInfo::default()).await?;
+
+    let response = RawUploadResponse {
+        id: file_id,
+        name: file_name,
+        mime_type,
+        url: (!multi_use).then(|| format!("{}/f/{}/{}", state.base_url, tok_path, file_id)),
+    };
+    Ok(raw_upload_response(&headers, response))
+}
+
+/// The url of the file as text, or everything as json if the client asks for it.
+fn raw_upload_response(headers: &HeaderMap, response: RawUploadResponse) -> Response {
+    let wants_json = headers
+        .get(header::ACCEPT)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.contains("application/json"));
+
+    if wants_json {
+        return axum::Json(response).into_response();
+    }
+
+    match response.url {
+        Some(url) => format!("{url}\n").into_response(),
+        None => "Thank you, your file has been received.\n".into_response(),
+    }
+}
+
+/// The type sent by the client, or guessed from the name of the file since
+/// curl doesn't send any with `-T`, and a form type with `--data-binary`.
+fn raw_upload_mime_type(headers: &HeaderMap, file_name: &str) -> Option<String> {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .filter(|m| !m.is_empty() && *m != "application/x-www-form-urlencoded")
+        .map(str::to_string)
+        .or_else(|| {
+            mime_guess::from_path(file_name)
+                .first()
+                .map(|m| m.essence_str().to_string())
+        })
+}
+
+/// Plain text error, for the clients which don't go through the form.
+fn reject_raw_upload(rejection: UploadRejection) -> Response {
+    tracing::info!("Raw upload rejected: {rejection}");
+    let status = match rejection {
+        UploadRejection::FileTooLarge { .. } | UploadRejection::TotalTooLarge { .. } => {
+            hyper::StatusCode::PAYLOAD_TOO_LARGE
+        }
+        _ => hyper::StatusCode::BAD_REQUEST,
+    };
+    (status, format!("{rejection}\n")).into_response()
+}
+
+fn multipart_io_error(err: MultipartError) -> std::io::Error {
+    std::io::Error::other(format!("multipart error: {err}"))
+}
+
+/// Stores the files of one upload attempt for a token, keeping track of its limits.
+struct UploadSession<'a> {
+    state: &'a AppState,
+    backend: Box<dyn StorageBackend + Send + Sync>,
+    token: UploadToken,
+    restrictions: UploadRestrictions,
+    strip_metadata: bool,
+    keep_original: bool,
+    total_bytes: u64,
+    file_idx: u64,
+    file_count: i64,
+    thumbnail_sources: Vec<thumbnail::SourceFile>,
+    _active: ActiveUpload,
+}
+
+impl<'a> UploadSession<'a> {
+    async fn start(state: &'a AppState, token: DbToken) -> Result<UploadSession<'a>> {
+        let backend = state
+            .get_backend(&token.backend_type)
+            .ok_or_else(|| AppError::UnknownStorageBackend(token.backend_type.clone()))?;
+
+        let restrictions = UploadRestrictions::from(&token);
+        let strip_metadata = token.strip_metadata;
+        let keep_original = token.keep_original;
+        let token = state.db.initiate_upload(token).await?;
+
+        Ok(Self {
+            state,
+            backend,
+            token,
+            restrictions,
+            strip_metadata,
+            keep_original,
+            total_bytes: 0,
+            file_idx: 0,
+            file_count: 0,
+            thumbnail_sources: Vec::new(),
+            _active: ActiveUpload::start(),
+        })
+    }
+
+    /// Stream a file to the storage backend. Returns the id of the new file,
+    /// or None if nothing was sent, like for an empty file input.
+    async fn store_file<S>(
+        &mut self,
+        mime_type: Option<&str>,
+        file_name: Option<&str>,
+        body: S,
+    ) -> Result<std::result::Result<Option<i64>, UploadRejection>>
+    where
+        S: futures::TryStream<Ok = Bytes, Error = std::io::Error> + Unpin,
+    {
+        let state = self.state;
+        let backend = &self.backend;
+        let token = &self.token;
+
+        self.file_idx += 1;
+        let file_idx = self.file_idx;
         tracing::info!("mime type: {mime_type:?}");
+
+        // browsers send an empty filename for file inputs left empty, these
+        // are discarded later since no bytes are uploaded for them.
+        if let Some(name) = file_name.filter(|n| !n.is_empty()) {
+            self.file_count += 1;
+            if let Err(rejection) = self
+                .restrictions
+                .check_file(self.file_count, mime_type, name)
+            {
+                return Ok(Err(rejection));
+            }
+        }
+
         let init_file = InitFile {
             token_id: token.id,
             token_path: &token.path,
             file_index: file_idx,
             attempt_counter: token.attempt_counter,
             mime_type,
-            file_name: field.file_name(),
+            file_name,
+            variant: None,
         };
 
         let (writer, data) = backend.initiate_upload(&init_file).await?;
@@ -184,55 +642,449 @@ pub(crate) async fn post_upload_form(
         let db_file = state
             .db
             .create_file(
-                &token,
+                token,
                 backend.get_type(),
                 data.clone(),
                 mime_type,
-                field.file_name(),
+                file_name,
             )
             .await?;
 
+        // the unmodified file is stored next to the cleaned one, for the admins
+        let strip = self.strip_metadata && mime_type.is_some_and(strip::can_strip);
+        let mut original = if strip && self.keep_original {
+            let init_original = InitFile {
+                variant: Some(strip::ORIGINAL_KIND),
+                ..init_file
+            };
+            let (w, d) = backend.initiate_upload(&init_original).await?;
+            Some((w.compat_write(), d))
+        } else {
+            None
+        };
+        let mut stripper = strip.then(MetadataStripper::new);
+
         let mime_type = mime_type.map(str::to_string);
+        let file_name = file_name.unwrap_or_default().to_string();
 
-        let reader =
-            field.map_err(|err| std::io::Error::new(ErrorKind::Other, format!("oops {err:?}")));
-        let bytes_copied =
-            futures::io::copy_buf(&mut reader.into_async_read(), &mut writer).await?;
-        total_bytes += bytes_copied;
+        let limit = self.restrictions.field_limit(self.total_bytes);
+        let mut field_bytes = 0;
+        let reader = body.and_then(|chunk| {
+            field_bytes += chunk.len() as u64;
+            let res = match limit {
+                Some((max, _)) if field_bytes > max => Err(std::io::Error::other(LimitExceeded)),
+                _ => Ok(chunk),
+            };
+            futures::future::ready(res)
+        });
+        // both the reading from the client and the writing to the backend
+        let copy_span = tracing::info_span!("copy_file", backend = backend.get_type());
+        let copy_result = match stripper.as_mut() {
+            None => futures::io::copy_buf(&mut reader.into_async_read(), &mut writer)
+                .instrument(copy_span)
+                .await
+                .map(|n| (n, n)),
+            Some(stripper) => {
+                copy_stripped(
+                    reader,
+                    &mut writer,
+                    stripper,
+                    original.as_mut().map(|o| &mut o.0),
+                )
+                .instrument(copy_span)
+                .await
+            }
+        };
+        let (bytes_copied, bytes_stored) = match copy_result {
+            Ok(n) => n,
+            Err(err) => {
+                // nothing is kept of a partial file
+                backend.delete_blob(data).await?;
+                if let Some((_, original_data)) = original {
+                    backend.delete_blob(original_data).await?;
+                }
+                state.db.delete_files([db_file.id]).await?;
+                return match limit {
+                    Some((_, kind)) if is_limit_exceeded(&err) => {
+                        let rejection = match kind {
+                            LimitKind::PerFile(max_mib) => UploadRejection::FileTooLarge {
+                                name: file_name,
+                                max_mib,
+                            },
+                            LimitKind::Total(max_mib) => UploadRejection::TotalTooLarge { max_mib },
+                        };
+                        Ok(Err(rejection))
+                    }
+                    _ => Err(err.into()),
+                };
+            }
+        };
+        self.total_bytes += bytes_copied;
+        metrics().add_uploaded_bytes(backend.get_type(), bytes_copied);
 
         if bytes_copied == 0 {
-            tracing::info!("No bytes uploaded for token {} - {}", token.id, token.path);
+            tracing::info!(
+                token_id = token.id,
+                file_id = db_file.id,
+                "No bytes uploaded"
+            );
             backend.delete_blob(data).await?;
+            if let Some((_, original_data)) = original {
+                backend.delete_blob(original_data).await?;
+            }
             state.db.delete_files([db_file.id]).await?;
-        } else {
-            let mb_data = writer.into_inner().finalize_upload().await?;
-            let metadata = DbFileMetadata {
-                size_b: Some(bytes_copied as _),
-                mime_type,
-            };
+            return Ok(Ok(None));
+        }
+
+        let mb_data = writer.into_inner().finalize_upload().await?;
+        let original = match original {
+            Some((w, d)) => Some(w.into_inner().finalize_upload().await?.unwrap_or(d)),
+            None => None,
+        };
+        let source = thumbnail::SourceFile {
+            file_id: db_file.id,
+            token_id: token.id,
+            token_path: token.path.clone(),
+            attempt_counter: token.attempt_counter,
+            file_index: file_idx,
+            mime_type: mime_type.clone(),
+            backend_type: db_file.backend_type.clone(),
+            backend_data: mb_data.clone().unwrap_or(data),
+        };
+        let metadata = DbFileMetadata {
+            size_b: Some(bytes_stored as _),
+            mime_type,
+            captured_at: None,
+        };
+        let file_id = db_file.id;
+        state
+            .db
+            .finalise_file_upload(db_file, mb_data, metadata)
+            .await?;
+        if let Some(original_data) = original {
             state
                 .db
-                .finalise_file_upload(db_file, mb_data, metadata)
+                .create_derived_file(
+                    file_id,
+                    strip::ORIGINAL_KIND,
+                    source
+                        .mime_type
+                        .as_deref()
+                        .unwrap_or("application/octet-stream"),
+                    backend.get_type(),
+                    original_data,
+                )
                 .await?;
-
-            tracing::info!("total uploaded for field: {}Kib", bytes_copied / 1024);
         }
-    }
+        self.thumbnail_sources.push(source);
 
-    if total_bytes == 0 {
         tracing::info!(
-            "No bytes uploaded at all for token {} - {}",
-            token.id,
-            token.path
+            token_id = token.id,
+            file_id,
+            backend = backend.get_type(),
+            bytes = bytes_copied,
+            "File uploaded"
         );
+        Ok(Ok(Some(file_id)))
+    }
+
+    /// Mark the token as used if anything was uploaded, returns whether that was the case.
+    async fn finish(self, uploader: UploaderInfo) -> Result<bool> {
+        if self.total_bytes == 0 {
+            tracing::info!(token_id = self.token.id, "No bytes uploaded at all");
+            return Ok(false);
+        }
+
+        let token_id = self.token.id;
+        self.state
+            .db
+            .finalise_token_upload(self.token, uploader)
+            .await?;
+        tracing::info!(token_id, bytes = self.total_bytes, "Upload done");
+        // only once the upload is complete, to avoid competing with it for the db
+        for source in self.thumbnail_sources {
+            thumbnail::spawn_generation(self.state.clone(), source);
+        }
+        Ok(true)
+    }
+}
+
+const MIB: u64 = 1024 * 1024;
+
+/// name of the optional multipart text fields sent alongside the files
+const UPLOADER_NAME_FIELD: &str = "uploader-name";
+const UPLOADER_EMAIL_FIELD: &str = "uploader-email";
+const MESSAGE_FIELD: &str = "message";
+
+/// text typed or pasted in the form, stored as a text file
+const PASTE_FIELD: &str = "paste";
+const PASTE_NAME_FIELD: &str = "paste-name";
+/// the extension of the language of the paste, used for the highlighting
+const PASTE_LANGUAGE_FIELD: &str = "paste-language";
+const PASTE_MIME_TYPE: &str = "text/plain";
+const DEFAULT_PASTE_NAME: &str = "paste";
+/// extension and name of the languages offered in the form
+const PASTE_LANGUAGES: [(&str, &str); 16] = [
+    ("log", "Log"),
+    ("md", "Markdown"),
+    ("json", "JSON"),
+    ("yaml", "YAML"),
+    ("toml", "TOML"),
+    ("xml", "XML"),
+    ("html", "HTML"),
+    ("css", "CSS"),
+    ("sh", "Shell"),
+    ("py", "Python"),
+    ("js", "JavaScript"),
+    ("rs", "Rust"),
+    ("go", "Go"),
+    ("java", "Java"),
+    ("c", "C"),
+    ("sql", "SQL"),
+];
+
+/// Name of the file for a paste. The extension is what drives the highlighting, so
+/// the language is added to the name if it doesn't have one already.
+fn paste_file_name(name: Option<&str>, language: Option<&str>) -> String {
+    let name = name
+        .map(|n| n.trim().replace(['/', '\\'], "_"))
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| DEFAULT_PASTE_NAME.to_string());
+    let language = language
+        .map(|l| l.trim().trim_start_matches('.'))
+        .filter(|l| !l.is_empty() && l.chars().all(|c| c.is_ascii_alphanumeric()));
+    match language {
+        Some(ext) if !name.contains('.') => format!("{name}.{ext}"),
+        None if !name.contains('.') => format!("{name}.txt"),
+        _ => name,
+    }
+}
+
+/// Copy the uploaded bytes without the metadata, and the unmodified bytes to
+/// `original` if set. Returns how many bytes were received and how many were stored.
+async fn copy_stripped<S, W>(
+    reader: S,
+    writer: &mut W,
+    stripper: &mut MetadataStripper,
+    mut original: Option<&mut W>,
+) -> std::io::Result<(u64, u64)>
+where
+    S: futures::TryStream<Ok = axum::body::Bytes, Error = std::io::Error>,
+    W: futures::AsyncWrite + Unpin,
+{
+    let mut reader = std::pin::pin!(reader.into_stream());
+    let mut received = 0;
+    let mut stored = 0;
+    while let Some(chunk) = reader.try_next().await? {
+        received += chunk.len() as u64;
+        if let Some(original) = original.as_mut() {
+            original.write_all(&chunk).await?;
+        }
+        let cleaned = stripper.feed(&chunk);
+        stored += cleaned.len() as u64;
+        writer.write_all(&cleaned).await?;
+    }
+    let rest = stripper.finish();
+    stored += rest.len() as u64;
+    writer.write_all(&rest).await?;
+
+    writer.flush().await?;
+    if let Some(original) = original {
+        original.flush().await?;
+    }
+    Ok((received, stored))
+}
+
+/// maximum length, in characters, of the text fields
+const MAX_NAME_LEN: usize = 100;
+const MAX_EMAIL_LEN: usize = 254;
+const MAX_MESSAGE_LEN: usize = 2000;
+const MAX_PASTE_NAME_LEN: usize = 255;
+
+/// Read a text field from the form, without buffering more than what the limit allows.
+/// The value is trimmed, and None if empty.
+async fn read_text_field(
+    mut field: Field<'_>,
+    label: &'static str,
+    max_len: usize,
+) -> Result<std::result::Result<Option<String>, UploadRejection>> {
+    let too_long = UploadRejection::FieldTooLong {
+        field: label,
+        max: max_len,
+    };
+    let mut buf = Vec::new();
+    while let Some(chunk) = field.chunk().await? {
+        buf.extend_from_slice(&chunk);
+        // a character is at most 4 bytes in utf8
+        if buf.len() > max_len * 4 {
+            return Ok(Err(too_long));
+        }
+    }
+
+    let value = String::from_utf8_lossy(&buf);
+    let value = value.trim();
+    if value.chars().count() > max_len {
+        Ok(Err(too_long))
+    } else if value.is_empty() {
+        Ok(Ok(None))
     } else {
-        state.db.finalise_token_upload(token).await?;
-        tracing::info!("done with upload");
+        Ok(Ok(Some(value.to_string())))
     }
+}
 
-    // TODO: maybe use https://docs.rs/axum/0.6.0-rc.4/axum/extract/struct.OriginalUri.html
-    // instead of reconstructing the path here
-    Ok(Redirect::to(&format!("/f/{}", tok_path)).into_response())
+/// Not trying to be exhaustive here, only to catch obvious typos
+fn is_valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((user, domain)) => {
+            !user.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !email.contains(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+fn reject_upload(flash: Flash, tok_path: &str, rejection: UploadRejection) -> Response {
+    tracing::info!(token_path = tok_path, "Upload rejected: {rejection}");
+    let redirect = Redirect::to(&format!("/f/{}", tok_path));
+    (flash.error(rejection.to_string()), redirect).into_response()
+}
+
+/// The limits set on a token when it was created, checked for every uploaded file
+struct UploadRestrictions {
+    allowed_types: Option<String>,
+    max_files: Option<i64>,
+    max_file_size_mib: Option<i64>,
+    max_size_mib: Option<i64>,
+}
+
+/// Which limit applies to the file currently being uploaded
+#[derive(Debug, Clone, Copy)]
+enum LimitKind {
+    PerFile(i64),
+    Total(i64),
+}
+
+/// Reasons to refuse an upload. The messages are displayed as is to the uploader.
+#[derive(thiserror::Error, Debug)]
+enum UploadRejection {
+    #[error("At most {max} file(s) can be uploaded here.")]
+    TooManyFiles { max: i64 },
+
+    #[error("The file {name} is too big, each file must be at most {max_mib} MiB.")]
+    FileTooLarge { name: String, max_mib: i64 },
+
+    #[error("The files are too big, all files together must be at most {max_mib} MiB.")]
+    TotalTooLarge { max_mib: i64 },
+
+    #[error("The file {name} ({mime}) is not accepted here, allowed types are: {allowed}.")]
+    TypeNotAllowed {
+        name: String,
+        mime: String,
+        allowed: String,
+    },
+
+    #[error("The {field} is too long, it must be at most {max} characters.")]
+    FieldTooLong { field: &'static str, max: usize },
+
+    #[error("The email address {email} doesn't look valid.")]
+    InvalidEmail { email: String },
+}
+
+/// Error used to abort the copy of a field when it goes over the size limit
+#[derive(thiserror::Error, Debug)]
+#[error("upload size limit exceeded")]
+struct LimitExceeded;
+
+fn is_limit_exceeded(err: &std::io::Error) -> bool {
+    err.get_ref()
+        .map(|e| e.is::<LimitExceeded>())
+        .unwrap_or(false)
+}
+
+impl std::convert::From<&DbToken> for UploadRestrictions {
+    fn from(tok: &DbToken) -> Self {
+        Self {
+            allowed_types: tok.allowed_types.clone(),
+            max_files: tok.max_files,
+            max_file_size_mib: tok.max_file_size_mib,
+            max_size_mib: tok.max_size_mib,
+        }
+    }
+}
+
+impl UploadRestrictions {
+    /// check the restrictions which can be known before receiving any bytes
+    fn check_file(
+        &self,
+        file_count: i64,
+        mime_type: Option<&str>,
+        file_name: &str,
+    ) -> std::result::Result<(), UploadRejection> {
+        if let Some(max) = self.max_files {
+            if file_count > max {
+                return Err(UploadRejection::TooManyFiles { max });
+            }
+        }
+
+        if let Some(allowed) = &self.allowed_types {
+            if !is_type_allowed(allowed, mime_type, file_name) {
+                return Err(UploadRejection::TypeNotAllowed {
+                    name: file_name.to_string(),
+                    mime: mime_type.unwrap_or("unknown type").to_string(),
+                    allowed: allowed.to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How many bytes the next file can have, given what has already been uploaded,
+    /// and which limit it is.
+    fn field_limit(&self, uploaded_bytes: u64) -> Option<(u64, LimitKind)> {
+        // the tokens are created with positive limits, anything else is ignored
+        let bytes = |mib: i64| u64::try_from(mib).ok()?.checked_mul(MIB);
+        let per_file = self
+            .max_file_size_mib
+            .and_then(|m| Some((bytes(m)?, LimitKind::PerFile(m))));
+        let total = self.max_size_mib.and_then(|m| {
+            Some((
+                bytes(m)?.saturating_sub(uploaded_bytes),
+                LimitKind::Total(m),
+            ))
+        });
+        match (per_file, total) {
+            (Some(f), Some(t)) => Some(if f.0 <= t.0 { f } else { t }),
+            (f, t) => f.or(t),
+        }
+    }
+}
+
+/// `allowed` is a comma separated list of mime types (application/pdf),
+/// mime prefixes (image/*) or file extensions (.pdf), the same format as the
+/// accept attribute of an html file input.
+fn is_type_allowed(allowed: &str, mime_type: Option<&str>, file_name: &str) -> bool {
+    let mime_type = mime_type.map(str::to_lowercase);
+    let file_name = file_name.to_lowercase();
+    allowed
+        .split(',')
+        .map(|t| t.trim().to_lowercase())
+        .filter(|t| !t.is_empty())
+        .any(|t| {
+            if t.starts_with('.') {
+                file_name.ends_with(&t)
+            } else if let Some(prefix) = t.strip_suffix("/*") {
+                mime_type
+                    .as_deref()
+                    .and_then(|m| m.split_once('/'))
+                    .map(|(p, _)| p == prefix)
+                    .unwrap_or(false)
+            } else {
+                mime_type.as_deref() == Some(t.as_str())
+            }
+        })
 }
 
 async fn upload_form(
@@ -240,13 +1092,25 @@ async fn upload_form(
     incoming_flashes: IncomingFlashes,
     tok: DbToken,
 ) -> Result<Response> {
-    tracing::info!("fresh token {} - {}", tok.id, tok.path);
+    tracing::info!(token_id = tok.id, "Fresh token");
     let now = OffsetDateTime::now_utc();
     let duration = tok.valid_until - now;
     let duration = std::time::Duration::from_secs(duration.as_seconds_f64().round() as u64);
 
     let mut ctx = ctx_from_flashes(&incoming_flashes);
     ctx.insert("max_size", &tok.max_size_mib);
+    ctx.insert("max_file_size", &tok.max_file_size_mib);
+    ctx.insert("max_files", &tok.max_files);
+    ctx.insert("allowed_types", &tok.allowed_types);
+    ctx.insert("multi_use", &tok.multi_use);
+    ctx.insert("strip_metadata", &tok.strip_metadata);
+    ctx.insert("title", &tok.title);
+    ctx.insert("description", &tok.description);
+    ctx.insert("max_name_len", &MAX_NAME_LEN);
+    ctx.insert("max_email_len", &MAX_EMAIL_LEN);
+    ctx.insert("max_message_len", &MAX_MESSAGE_LEN);
+    ctx.insert("max_paste_name_len", &MAX_PASTE_NAME_LEN);
+    ctx.insert("paste_languages", &PASTE_LANGUAGES);
     ctx.insert("valid_for", &format_duration(duration).to_string());
     if let Some(d) = tok.content_expires_after_hours {
         let d = std::time::Duration::new((d as u64) * 3600, 0);
@@ -303,11 +1167,41 @@ async fn get_files_html(
 
     ctx.insert("token_path", &tok.path);
 
-    let files = state.db.get_files(tok.id, tok.attempt_counter).await?;
-    let files: Vec<TplFile> = files.into_iter().map(|x| x.into()).collect();
+    let mut files = state.db.get_files(tok.id, tok.attempt_counter).await?;
+    let with_thumbnail = state
+        .db
+        .get_file_ids_with_derived(tok.id, tok.attempt_counter, DerivedKind::Thumbnail.as_str())
+        .await?;
 
+    // mostly pictures, like after a wedding, are better seen as a grid
+    let image_count = files.iter().filter(|(f, _)| is_image(f)).count();
+    let gallery = image_count > 1 && image_count * 2 > files.len();
+    if gallery {
+        // in the order they were taken, the ones without exif data at the end
+        files.sort_by_key(|(f, m)| (!is_image(f), m.captured_at.is_none(), m.captured_at, f.id));
+    }
+
+    let (images, files): (Vec<TplFile>, Vec<TplFile>) = files
+        .into_iter()
+        .map(|x| {
+            let mut f: TplFile = x.into();
+            f.thumbnail = with_thumbnail.contains(&f.id);
+            f
+        })
+        .partition(|f| gallery && f.mime_prefix.as_deref() == Some("image"));
+
+    if let Some(batch) = state.db.get_batch(tok.id, tok.attempt_counter).await? {
+        ctx.insert("uploader_name", &batch.uploader_name);
+        ctx.insert("uploader_email", &batch.uploader_email);
+        ctx.insert("uploader_message", &batch.message);
+    }
+
+    ctx.insert("gallery", &gallery);
+    ctx.insert("images", &images);
     ctx.insert("files", &files);
     ctx.insert("tok_path", &tok.path);
+    ctx.insert("title", &tok.title);
+    ctx.insert("description", &tok.description);
 
     let html: Html<String> = state
         .templates
@@ -317,6 +1211,93 @@ async fn get_files_html(
     Ok((incoming_flashes, html).into_response())
 }
 
+fn is_image(file: &DbFile) -> bool {
+    file.mime_type
+        .as_deref()
+        .is_some_and(|m| m.starts_with("image/"))
+}
+
+/// How to render an upload batch, and its files, in a template
+#[derive(serde::Serialize, Debug)]
+struct TplBatch {
+    created_at: String,
+    uploader_name: Option<String>,
+    uploader_email: Option<String>,
+    message: Option<String>,
+    files: Vec<TplFile>,
+}
+
+/// All the uploads for an inbox token. Only for admins since an inbox collects
+/// files from several people who shouldn't see each other's files.
+#[tracing::instrument(skip(state, incoming_flashes, _admin))]
+pub(crate) async fn get_batches(
+    state: State<AppState>,
+    incoming_flashes: IncomingFlashes,
+    _admin: Admin,
+    Path(tok_path): Path<String>,
+) -> Result<Response> {
+    let tok_path =
+        urlencoding::decode(&tok_path).map_err(|e| crate::error::AppError::InvalidUrlToken {
+            token: tok_path.clone(),
+            source: e,
+        })?;
+
+    let tok = match state.db.get_valid_token(&tok_path).await? {
+        GetTokenResult::Fresh(tok) | GetTokenResult::Used(tok) if tok.multi_use => tok,
+        _ => {
+            let html: Html<String> = state
+                .templates
+                .read()
+                .render("no_link_found.html", &tera::Context::new())?
+                .into();
+            let rsp = (hyper::StatusCode::NOT_FOUND, html);
+            return Ok((incoming_flashes, rsp).into_response());
+        }
+    };
+
+    let fmt = time::macros::format_description!("[year]/[month]/[day] [hour]:[minute]");
+    let mut batches = Vec::new();
+    for batch in state.db.get_batches(tok.id).await? {
+        let files = state.db.get_files(tok.id, batch.attempt_counter).await?;
+        let with_original = state
+            .db
+            .get_file_ids_with_derived(tok.id, batch.attempt_counter, strip::ORIGINAL_KIND)
+            .await?;
+        batches.push(TplBatch {
+            created_at: batch
+                .created_at
+                .format(&fmt)
+                .expect("formatting offsetdatetime"),
+            uploader_name: batch.uploader_name,
+            uploader_email: batch.uploader_email,
+            message: batch.message,
+            files: files
+                .into_iter()
+                .map(|x| {
+                    let mut f: TplFile = x.into();
+                    f.original = with_original.contains(&f.id);
+                    f
+                })
+                .collect(),
+        });
+    }
+
+    let mut ctx = ctx_from_flashes(&incoming_flashes);
+    ctx.insert("tok_path", &tok.path);
+    ctx.insert("batches", &batches);
+    ctx.insert(
+        "accepts_uploads",
+        &(tok.valid_until > OffsetDateTime::now_utc()),
+    );
+
+    let html: Html<String> = state
+        .templates
+        .read()
+        .render("get_batches.html", &ctx)?
+        .into();
+    Ok((incoming_flashes, html).into_response())
+}
+
 trait IntoIOError {
     // fn into_io_error<E: std::error::Error + Send + Sync + 'static>(self: E) -> std::io::Error;
     fn into_io_error(self) -> std::io::Error;
@@ -324,7 +1305,7 @@ trait IntoIOError {
 
 impl IntoIOError for ZipError {
     fn into_io_error(self) -> std::io::Error {
-        std::io::Error::new(std::io::ErrorKind::Other, self)
+        std::io::Error::other(self)
     }
 }
 
@@ -336,13 +1317,13 @@ impl IntoIOError for crate::error::AppError {
 }
 
 #[pin_project]
-struct ZipAsyncReader {
+struct ArchiveAsyncReader {
     #[pin]
     rdr: Compat<DuplexStream>,
     fut_wrt: Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>,
 }
 
-impl futures::io::AsyncRead for ZipAsyncReader {
+impl futures::io::AsyncRead for ArchiveAsyncReader {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -359,50 +1340,172 @@ impl futures::io::AsyncRead for ZipAsyncReader {
     }
 }
 
+/// The files to put in an archive: all of them, or the ones chosen with
+/// `?ids=1,4,7` (or `?ids=1&ids=4`, as sent by the form).
+/// The error is for the ids which don't belong to this token.
+async fn archive_files(
+    state: &AppState,
+    tok: &DbToken,
+    raw_query: Option<&str>,
+) -> Result<std::result::Result<Vec<(DbFile, DbFileMetadata)>, String>> {
+    let files = state.db.get_files(tok.id, tok.attempt_counter).await?;
+
+    let mut ids = Vec::new();
+    let values = url::form_urlencoded::parse(raw_query.unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key == "ids")
+        .map(|(_, value)| value.into_owned());
+    for value in values {
+        for id in value.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+            match id.parse::<i64>() {
+                Ok(id) => ids.push(id),
+                Err(_) => return Ok(Err(format!("Invalid file id: {id}\n"))),
+            }
+        }
+    }
+    if ids.is_empty() {
+        return Ok(Ok(files));
+    }
+
+    for id in &ids {
+        let valid = state
+            .db
+            .get_valid_file(&tok.path, *id)
+            .await?
+            .is_some_and(|f| f.attempt_counter == tok.attempt_counter);
+        if !valid {
+            return Ok(Err(format!("No file {id} here\n")));
+        }
+    }
+
+    Ok(Ok(files
+        .into_iter()
+        .filter(|(file, _)| ids.contains(&file.id))
+        .collect()))
+}
+
+/// Compressed with deflate, the length of the archive is only known at the end.
 async fn get_files_zip(
     state: State<AppState>,
     incoming_flashes: IncomingFlashes,
     tok: DbToken,
+    files: Vec<(DbFile, DbFileMetadata)>,
 ) -> Result<Response> {
-    let files = state.db.get_files(tok.id, tok.attempt_counter).await?;
-
     let state = state.clone();
     let (rdr, wrt) = tokio::io::duplex(4096);
     let fut = async move {
-        let mut zip_wrt = async_zip::base::write::ZipFileWriter::new(wrt.compat());
+        let mut zip_wrt = async_zip::base::write::ZipFileWriter::new(wrt.compat_write());
         for (file, _metadata) in files {
-            match file.backend_type.as_str() {
-                "local_fs" => {
-                    let data = serde_json::from_str(&file.backend_data)?;
-                    let blob = state
-                        .garage
-                        .read_blob(data)
-                        .await
-                        .map_err(|e| e.into_io_error())?
-                        .compat();
-                    let filename = file.name.unwrap_or_else(|| format!("{}", file.id));
-                    let opts = ZipEntryBuilder::new(filename.into(), Compression::Deflate);
-                    let mut entry = zip_wrt
-                        .write_entry_stream(opts)
-                        .await
-                        .map_err(|e| e.into_io_error())?;
-                    futures::io::copy(blob, &mut entry).await?;
-                    entry.close().await.map_err(|e| e.into_io_error())?;
-                }
-                x => {
-                    tracing::error!("Unexpected backend type {} for file {}", x, file.id);
-                    return Err(AppError::UnknownStorageBackend(x.to_string()).into_io_error());
-                }
+            let blob = state
+                .get_blob(&file.backend_type, file.backend_data)
+                .await
+                .map_err(|e| e.into_io_error())?;
+            let blob = count_download(&file.backend_type, blob).compat();
+            let filename = file.name.unwrap_or_else(|| format!("{}", file.id));
+            let mut opts = ZipEntryBuilder::new(filename.into(), Compression::Deflate);
+            if let Some(created_at) =
+                chrono::DateTime::from_timestamp(file.created_at.unix_timestamp(), 0)
+            {
+                opts = opts.last_modification_date(ZipDateTime::from_chrono(&created_at));
             }
+            let mut entry = zip_wrt
+                .write_entry_stream(opts)
+                .await
+                .map_err(|e| e.into_io_error())?;
+            futures::io::copy(blob, &mut entry).await?;
+            entry.close().await.map_err(|e| e.into_io_error())?;
         }
+        zip_wrt
+            .close()
+            .await
+            .map_err(|e| e.into_io_error())?
+            .close()
+            .await
+    };
 
-        zip_wrt.close().await.map_err(|e| e.into_io_error())?;
+    let zar = ArchiveAsyncReader {
+        rdr: rdr.compat(),
+        fut_wrt: Box::pin(fut.fuse()),
+    };
+
+    let stream = tokio_util::io::ReaderStream::new(zar.compat());
+    let body = axum::body::StreamBody::new(stream);
+
+    Ok((incoming_flashes, (zip_headers(&tok), body)).into_response())
+}
+
+/// Stored without compression, so that the length of the response is known
+/// up front and browsers can show the progress of the download.
+async fn get_files_zip_stored(
+    state: State<AppState>,
+    incoming_flashes: IncomingFlashes,
+    tok: DbToken,
+    files: Vec<(DbFile, DbFileMetadata)>,
+) -> Result<Response> {
+    let mut entries = Vec::new();
+    for (file, metadata) in files {
+        // the sizes are needed to compute the length of the archive
+        let Some(size) = metadata.size_b else {
+            tracing::warn!(file_id = file.id, "Unknown size, not added to the zip");
+            continue;
+        };
+        let entry = ZipEntry {
+            name: file.name.clone().unwrap_or_else(|| format!("{}", file.id)),
+            size: size as u64,
+            modified_at: file.created_at,
+        };
+        entries.push((file, entry));
+    }
+
+    let content_length = zip::archive_len(entries.iter().map(|(_, entry)| entry));
+
+    let state = state.clone();
+    let (rdr, wrt) = tokio::io::duplex(4096);
+    let fut = async move {
+        let mut wrt = wrt.compat_write();
+        let mut zip_wrt = ZipWriter::new();
+        let mut buf = vec![0; 64 * 1024];
+        for (file, entry) in entries {
+            let blob = state
+                .get_blob(&file.backend_type, file.backend_data)
+                .await
+                .map_err(|e| e.into_io_error())?;
+            let mut blob = count_download(&file.backend_type, blob).take(entry.size);
+            wrt.write_all(&zip_wrt.start_entry(&entry)).await?;
+
+            let mut hasher = crc32fast::Hasher::new();
+            let mut copied = 0;
+            loop {
+                let n = blob.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                wrt.write_all(&buf[..n]).await?;
+                copied += n as u64;
+            }
+            // the length of the response has already been sent, the archive can't be fixed
+            if copied != entry.size {
+                tracing::error!(
+                    file_id = file.id,
+                    backend = %file.backend_type,
+                    bytes = copied,
+                    expected_bytes = entry.size,
+                    "File shorter than expected in the zip"
+                );
+                return Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("file {} is shorter than expected", file.id),
+                ));
+            }
 
-        let result: std::io::Result<()> = Ok(());
-        result
+            wrt.write_all(&zip_wrt.finish_entry(&entry, hasher.finalize()))
+                .await?;
+        }
+        wrt.write_all(&zip_wrt.finish()).await?;
+        wrt.close().await
     };
 
-    let zar = ZipAsyncReader {
+    let zar = ArchiveAsyncReader {
         rdr: rdr.compat(),
         fut_wrt: Box::pin(fut.fuse()),
     };
@@ -410,6 +1513,13 @@ async fn get_files_zip(
     let stream = tokio_util::io::ReaderStream::new(zar.compat());
     let body = axum::body::StreamBody::new(stream);
 
+    let mut headers = zip_headers(&tok);
+    headers.insert(header::CONTENT_LENGTH, content_length.into());
+
+    Ok((incoming_flashes, (headers, body)).into_response())
+}
+
+fn zip_headers(tok: &DbToken) -> HeaderMap {
     let mut headers = HeaderMap::new();
     headers.insert(header::CONTENT_TYPE, "application/zip".parse().unwrap());
     headers.insert(
@@ -418,14 +1528,126 @@ async fn get_files_zip(
             .parse()
             .unwrap(),
     );
+    headers
+}
+
+/// Tarballs don't need a central directory, and the size of the files is known
+/// beforehand, so without compression the length of the response is sent up front.
+async fn get_files_tar(
+    state: State<AppState>,
+    incoming_flashes: IncomingFlashes,
+    tok: DbToken,
+    files: Vec<(DbFile, DbFileMetadata)>,
+    gzip: bool,
+) -> Result<Response> {
+    let mut entries = Vec::new();
+    for (file, metadata) in files {
+        // the size goes in the header, before the content
+        let Some(size) = metadata.size_b else {
+            tracing::warn!(file_id = file.id, "Unknown size, not added to the tarball");
+            continue;
+        };
+        let entry = TarEntry {
+            name: file.name.clone().unwrap_or_else(|| format!("{}", file.id)),
+            size: size as u64,
+            mtime: file
+                .completed_at
+                .unwrap_or(file.created_at)
+                .unix_timestamp(),
+        };
+        entries.push((file, entry));
+    }
+
+    let content_length = tar::archive_len(entries.iter().map(|(_, entry)| entry));
+
+    let state = state.clone();
+    let (rdr, wrt) = tokio::io::duplex(4096);
+    let fut = async move {
+        if gzip {
+            let mut wrt = GzipEncoder::new(wrt.compat_write());
+            write_tar(&state, entries, &mut wrt).await?;
+            wrt.close().await
+        } else {
+            let mut wrt = wrt.compat_write();
+            write_tar(&state, entries, &mut wrt).await?;
+            wrt.close().await
+        }
+    };
+
+    let tar = ArchiveAsyncReader {
+        rdr: rdr.compat(),
+        fut_wrt: Box::pin(fut.fuse()),
+    };
+
+    let stream = tokio_util::io::ReaderStream::new(tar.compat());
+    let body = axum::body::StreamBody::new(stream);
+
+    let (content_type, extension) = if gzip {
+        ("application/gzip", "tar.gz")
+    } else {
+        ("application/x-tar", "tar")
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}.{extension}\"", tok.path)
+            .parse()
+            .unwrap(),
+    );
+    if !gzip {
+        headers.insert(header::CONTENT_LENGTH, content_length.into());
+    }
 
     Ok((incoming_flashes, (headers, body)).into_response())
 }
 
+async fn write_tar<W>(
+    state: &AppState,
+    files: Vec<(DbFile, TarEntry)>,
+    wrt: &mut W,
+) -> std::io::Result<()>
+where
+    W: futures::AsyncWrite + Unpin,
+{
+    for (file, entry) in files {
+        let blob = state
+            .get_blob(&file.backend_type, file.backend_data)
+            .await
+            .map_err(|e| e.into_io_error())?;
+        let blob = count_download(&file.backend_type, blob);
+        wrt.write_all(&entry.header()).await?;
+        let copied = futures::io::copy(blob.take(entry.size).compat(), wrt).await?;
+        // the header has already been sent, the archive can't be fixed
+        if copied != entry.size {
+            tracing::error!(
+                file_id = file.id,
+                backend = %file.backend_type,
+                bytes = copied,
+                expected_bytes = entry.size,
+                "File shorter than expected in the tarball"
+            );
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("file {} is shorter than expected", file.id),
+            ));
+        }
+        wrt.write_all(&vec![0; tar::padding(entry.size)]).await?;
+    }
+    wrt.write_all(&tar::END_OF_ARCHIVE).await
+}
+
 #[derive(serde::Deserialize, Debug, Default)]
 pub(crate) struct FileQuery {
     #[serde(default, deserialize_with = "true_if_present")]
     zip: bool,
+    /// with `zip`, store the files without compression
+    #[serde(default, deserialize_with = "true_if_present")]
+    stored: bool,
+    #[serde(default, deserialize_with = "true_if_present")]
+    tar: bool,
+    #[serde(default, rename = "tar.gz", deserialize_with = "true_if_present")]
+    tar_gz: bool,
 }
 
 // if the field is present at all, treat it as true, and ignore any associated value
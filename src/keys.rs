@@ -0,0 +1,191 @@
+//! Keys signing the cookies. The first one signs the new cookies, the others come
+//! from before a rotation and are still accepted. Without a key in the config, they
+//! are read from a key file created on the first start.
+
+use std::{
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use base64::Engine;
+use cookie::{Cookie, CookieJar, Key};
+
+use crate::{
+    config::{decode_signing_key, Config, ConfigError},
+    state::AppState,
+};
+
+/// how many of the old keys are kept in the key file when rotating
+const MAX_PREVIOUS_KEYS: usize = 3;
+
+#[derive(Clone)]
+pub(crate) struct SigningKeys {
+    pub(crate) current: Key,
+    pub(crate) previous: Vec<Key>,
+}
+
+// don't leak the keys in the logs
+impl std::fmt::Debug for SigningKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKeys")
+            .field("previous", &self.previous.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SigningKeys {
+    /// The key from the config if there is one, otherwise the keys from the key
+    /// file, which is created if missing.
+    pub(crate) fn load(config: &Config) -> Result<Self, ConfigError> {
+        let mut keys = match &config.auth.signing_key {
+            Some(key) => vec![decode_signing_key("auth.signing_key", key)?],
+            None => read_key_file(&config.key_file())?,
+        };
+        for key in &config.auth.previous_signing_keys {
+            keys.push(decode_signing_key("auth.previous_signing_keys", key)?);
+        }
+
+        let mut keys = keys.iter().map(|key| Key::from(key));
+        let Some(current) = keys.next() else {
+            return Err(ConfigError::Invalid {
+                key: "auth.key_file",
+                reason: "doesn't contain any key".to_string(),
+            });
+        };
+        Ok(Self {
+            current,
+            previous: keys.collect(),
+        })
+    }
+}
+
+/// Put a new key in front of the ones in the key file, and forget the oldest ones.
+/// The server must be restarted to use it.
+pub fn rotate_key_file(path: &Path) -> Result<(), ConfigError> {
+    let existing = match std::fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(source) => return Err(key_file_error(path, source)),
+    };
+    let lines: Vec<String> = std::iter::once(generate_key())
+        .chain(
+            existing
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .map(str::to_string),
+        )
+        .take(1 + MAX_PREVIOUS_KEYS)
+        .collect();
+    write_key_file(path, &lines)
+}
+
+/// one base64 key per line, the current one first
+fn read_key_file(path: &Path) -> Result<Vec<Vec<u8>>, ConfigError> {
+    let raw = match std::fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            tracing::info!("Creating a new signing key in {}", path.display());
+            let key = generate_key();
+            write_key_file(path, std::slice::from_ref(&key))?;
+            key
+        }
+        Err(source) => return Err(key_file_error(path, source)),
+    };
+    raw.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| decode_signing_key("auth.key_file", l))
+        .collect()
+}
+
+fn generate_key() -> String {
+    base64::engine::general_purpose::STANDARD.encode(Key::generate().master())
+}
+
+/// Written in a temporary file first, so that a crash never leaves a truncated
+/// key file behind, and only readable by the owner.
+fn write_key_file(path: &Path, keys: &[String]) -> Result<(), ConfigError> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let write = || -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        for key in keys {
+            writeln!(file, "{key}")?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    };
+    write().map_err(|source| key_file_error(path, source))
+}
+
+fn key_file_error(path: &Path, source: std::io::Error) -> ConfigError {
+    ConfigError::KeyFile {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+/// The handlers only know about the current key, so the cookies signed with a
+/// previous key are signed again with the current one before reaching them.
+pub(crate) async fn accept_previous_keys<B>(
+    State(state): State<AppState>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !state.signing_keys.previous.is_empty() {
+        if let Some(cookies) = resign_cookies(&state.signing_keys, req.headers()) {
+            req.headers_mut().insert(header::COOKIE, cookies);
+        }
+    }
+    next.run(req).await
+}
+
+/// the new Cookie header, if any cookie was signed with a previous key
+fn resign_cookies(keys: &SigningKeys, headers: &HeaderMap) -> Option<HeaderValue> {
+    let mut jar = CookieJar::new();
+    for value in headers.get_all(header::COOKIE) {
+        let Ok(value) = value.to_str() else { continue };
+        for cookie in value.split(';') {
+            if let Ok(cookie) = Cookie::parse_encoded(cookie.trim().to_string()) {
+                jar.add_original(cookie);
+            }
+        }
+    }
+
+    let mut resigned = CookieJar::new();
+    for cookie in jar.iter() {
+        if jar.signed(&keys.current).get(cookie.name()).is_some() {
+            continue;
+        }
+        let plain = keys
+            .previous
+            .iter()
+            .find_map(|key| jar.signed(key).get(cookie.name()));
+        if let Some(plain) = plain {
+            resigned.signed_mut(&keys.current).add_original(plain);
+        }
+    }
+    resigned.iter().next()?;
+
+    let cookies = jar
+        .iter()
+        .map(|c| resigned.get(c.name()).unwrap_or(c))
+        .map(|c| c.encoded().stripped().to_string())
+        .collect::<Vec<_>>()
+        .join("; ");
+    HeaderValue::from_str(&cookies).ok()
+}
@@ -1,9 +1,23 @@
 pub mod state;
+pub mod config;
+pub mod keys;
 pub mod app;
 pub mod handlers;
 pub mod db;
 pub mod error;
 pub mod upload;
 pub mod cleanup;
+pub mod slug;
+pub mod dev;
+pub mod listener;
+pub mod logging;
+mod assets;
 mod filters;
+mod thumbnail;
+mod strip;
+mod preview;
+mod fetch;
+mod tar;
+mod zip;
+mod metrics;
 pub(crate) mod auth;
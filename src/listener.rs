@@ -0,0 +1,220 @@
+//! Listeners besides plain tcp: https with certificates reloaded on SIGHUP, and
+//! unix sockets.
+
+use std::{
+    fs::Permissions,
+    io::BufReader,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::server::accept::Accept;
+use parking_lot::RwLock;
+use tokio::{
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{self, Certificate, PrivateKey},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::config::{TlsConfig, UnixSocketConfig};
+
+/// clients which don't complete the handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// connections with a completed handshake waiting to be served
+const PENDING_CONNECTIONS: usize = 64;
+/// like hyper does, an error like too many open files would otherwise spin the loop
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(thiserror::Error, Debug)]
+pub enum ListenerError {
+    #[error("Cannot read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("No certificate found in {0}")]
+    NoCertificate(PathBuf),
+
+    #[error("No private key found in {0}")]
+    NoKey(PathBuf),
+
+    #[error("Invalid certificate or key: {0}")]
+    Tls(#[from] rustls::Error),
+
+    #[error("Cannot listen on {path}: {source}")]
+    Bind {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+/// The tls config used for the new connections, replaced when reloading.
+#[derive(Clone)]
+pub struct TlsCertificates {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: Arc<RwLock<Arc<rustls::ServerConfig>>>,
+}
+
+impl TlsCertificates {
+    pub fn load(config: &TlsConfig) -> Result<Self, ListenerError> {
+        let server_config = server_config(&config.cert, &config.key)?;
+        Ok(Self {
+            cert_path: config.cert.clone(),
+            key_path: config.key.clone(),
+            current: Arc::new(RwLock::new(Arc::new(server_config))),
+        })
+    }
+
+    /// Read the files again, like after a renewal. The established connections
+    /// keep the previous certificate.
+    pub fn reload(&self) -> Result<(), ListenerError> {
+        let server_config = server_config(&self.cert_path, &self.key_path)?;
+        *self.current.write() = Arc::new(server_config);
+        Ok(())
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().clone())
+    }
+}
+
+fn server_config(cert_path: &Path, key_path: &Path) -> Result<rustls::ServerConfig, ListenerError> {
+    let read_pem = |path: &Path| -> Result<Vec<rustls_pemfile::Item>, ListenerError> {
+        let file = std::fs::File::open(path).map_err(|source| ListenerError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|source| ListenerError::Read {
+            path: path.to_path_buf(),
+            source,
+        })
+    };
+
+    // the whole chain, the certificate of the server first
+    let certs: Vec<Certificate> = read_pem(cert_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(ListenerError::NoCertificate(cert_path.to_path_buf()));
+    }
+
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| ListenerError::NoKey(key_path.to_path_buf()))?;
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// The tls connections on the tcp listener. The handshakes are done in their
+/// own tasks, so that a slow client doesn't hold the others.
+pub struct TlsIncoming {
+    connections: mpsc::Receiver<TlsStream<TcpStream>>,
+}
+
+impl TlsIncoming {
+    pub fn new(listener: TcpListener, certificates: TlsCertificates) -> Self {
+        let (tx, rx) = mpsc::channel(PENDING_CONNECTIONS);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    // the server stopped
+                    _ = tx.closed() => break,
+                    res = listener.accept() => match res {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            tracing::warn!("Cannot accept connection: {err}");
+                            tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                            continue;
+                        }
+                    },
+                };
+                let acceptor = certificates.acceptor();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send(stream).await;
+                        }
+                        Ok(Err(err)) => tracing::debug!("TLS handshake failed with {addr}: {err}"),
+                        Err(_) => tracing::debug!("TLS handshake timed out with {addr}"),
+                    }
+                });
+            }
+        });
+        Self { connections: rx }
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream<TcpStream>;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.connections.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+pub struct UnixIncoming {
+    listener: UnixListener,
+}
+
+impl UnixIncoming {
+    /// A socket left by a previous run is replaced, but not any other file.
+    pub fn bind(config: &UnixSocketConfig) -> Result<Self, ListenerError> {
+        let bind_error = |source| ListenerError::Bind {
+            path: config.path.clone(),
+            source,
+        };
+        if let Ok(metadata) = std::fs::symlink_metadata(&config.path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(&config.path).map_err(bind_error)?;
+            }
+        }
+        let listener = UnixListener::bind(&config.path).map_err(bind_error)?;
+        std::fs::set_permissions(&config.path, Permissions::from_mode(config.mode))
+            .map_err(bind_error)?;
+        Ok(Self { listener })
+    }
+}
+
+impl Accept for UnixIncoming {
+    type Conn = UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.listener
+            .poll_accept(cx)
+            .map(|res| Some(res.map(|(stream, _)| stream)))
+    }
+}
@@ -0,0 +1,197 @@
+//! Text or json logs, request ids, and the optional export of the spans to an
+//! OpenTelemetry collector.
+
+use axum::{
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use hyper_tls::HttpsConnector;
+use opentelemetry::{trace::TraceError, KeyValue};
+use opentelemetry_http::hyper::HyperClient;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use password_hash::rand_core::{OsRng, RngCore};
+use tracing::{Span, Subscriber};
+use tracing_subscriber::{
+    layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+use crate::config::{LogFormat, OtlpConfig};
+
+pub(crate) const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// longer ids from the clients are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The levels of the logs come from RUST_LOG, like before. The spans are exported
+/// if the otlp config has an endpoint, must be called from the tokio runtime then.
+pub fn init(format: LogFormat, otlp: Option<&OtlpConfig>) -> Result<(), TraceError> {
+    let fmt = tracing_subscriber::fmt::layer();
+    let fmt = match format {
+        LogFormat::Text => fmt.boxed(),
+        // the fields of all the spans, not only the innermost one
+        LogFormat::Json => fmt.json().with_span_list(true).boxed(),
+    };
+    let otlp = match otlp {
+        Some(config) if config.endpoint.is_some() => Some(otlp_layer(config)?),
+        _ => None,
+    };
+    tracing_subscriber::registry()
+        .with(fmt.with_filter(EnvFilter::from_default_env()))
+        .with(otlp)
+        .init();
+    Ok(())
+}
+
+/// The exporter has its own filter so that the spans can be exported without
+/// the logs being verbose.
+fn otlp_layer<S>(config: &OtlpConfig) -> Result<impl Layer<S>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let tracer = otlp_tracer(config)?;
+
+    // an unreachable collector shouldn't go unnoticed, but isn't fatal
+    opentelemetry::global::set_error_handler(|err| {
+        tracing::warn!("Cannot export the spans: {err}");
+    })
+    .map_err(|err| TraceError::Other(Box::new(err)))?;
+
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(EnvFilter::new(&config.filter)))
+}
+
+/// The spans are sent in batches, in the background. Installed as the global
+/// tracer provider, which `shutdown` flushes.
+fn otlp_tracer(config: &OtlpConfig) -> Result<trace::Tracer, TraceError> {
+    let endpoint = config.endpoint.as_deref().unwrap_or_default();
+    let client = HyperClient::new_with_timeout(
+        hyper::Client::builder().build(HttpsConnector::new()),
+        config.timeout,
+    );
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_http_client(client)
+        // /v1/traces is added to it
+        .with_endpoint(endpoint.trim_end_matches('/'))
+        .with_timeout(config.timeout)
+        .with_headers(config.headers.clone());
+    let resource = Resource::new([KeyValue::new("service.name", config.service_name.clone())]);
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(runtime::Tokio)
+}
+
+/// Send the spans not exported yet. Blocks until done.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Keep the id given by the client (or a proxy) so that the logs can be
+/// correlated, otherwise generate one. It's also sent back in the response.
+pub(crate) async fn set_request_id<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .filter(|id| is_valid_request_id(id.as_bytes()))
+        .cloned()
+        .unwrap_or_else(generate_request_id);
+    req.headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id.clone());
+
+    let mut response = next.run(req).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    response
+}
+
+fn is_valid_request_id(id: &[u8]) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.iter().all(u8::is_ascii_graphic)
+}
+
+fn generate_request_id() -> HeaderValue {
+    let mut bytes = [0; 16];
+    OsRng.fill_bytes(&mut bytes);
+    let id: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    HeaderValue::from_str(&id).expect("hex is a valid header value")
+}
+
+/// The span of each request, for the TraceLayer. Must be inside
+/// `set_request_id` so that the header is always there.
+pub(crate) fn request_span<B>(req: &Request<B>) -> Span {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        request_id,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response,
+    };
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    /// a collector which only passes the exports along
+    fn collector() -> (String, mpsc::UnboundedReceiver<(String, Vec<u8>)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let path = format!("{} {}", req.method(), req.uri().path());
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        tx.send((path, body.to_vec())).unwrap();
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        (url, rx)
+    }
+
+    // shutdown blocks until the batch is sent, by the tasks on the other threads
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_exported_on_shutdown() {
+        let (endpoint, mut exports) = collector();
+        let config = OtlpConfig {
+            endpoint: Some(endpoint),
+            ..OtlpConfig::default()
+        };
+        let tracer = otlp_tracer(&config).unwrap();
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("test_upload").in_scope(|| {});
+        });
+
+        tokio::task::spawn_blocking(shutdown).await.unwrap();
+
+        let (path, body) = exports.try_recv().expect("the span was exported");
+        assert_eq!(path, "POST /v1/traces");
+        // protobuf, where the strings are as is
+        let contains = |s: &str| body.windows(s.len()).any(|w| w == s.as_bytes());
+        assert!(contains("test_upload"));
+        assert!(contains("vrac"));
+    }
+}
@@ -0,0 +1,225 @@
+//! Prometheus metrics for /metrics. The counters are updated as things happen, the
+//! database gauges when scraped.
+
+use std::{
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{error::Result, state::AppState};
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    uploaded_bytes: IntCounterVec,
+    downloaded_bytes: IntCounterVec,
+    active_uploads: IntGauge,
+    pub(crate) cleanup_duration: Histogram,
+    pub(crate) cleanup_deleted_files: IntCounter,
+    pub(crate) cleanup_failures: IntCounter,
+    db_connections: IntGauge,
+    db_idle_connections: IntGauge,
+    stored_bytes: IntGaugeVec,
+}
+
+/// the metrics of the process
+pub(crate) fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("vrac".to_string()), None).expect("the prefix is valid");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests handled"),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time to respond"),
+            &["method", "route"],
+        )
+        .expect("valid metric");
+        let uploaded_bytes = IntCounterVec::new(
+            Opts::new(
+                "uploaded_bytes_total",
+                "Bytes received for the uploaded files",
+            ),
+            &["backend"],
+        )
+        .expect("valid metric");
+        let downloaded_bytes = IntCounterVec::new(
+            Opts::new("downloaded_bytes_total", "Bytes of files sent"),
+            &["backend"],
+        )
+        .expect("valid metric");
+        let active_uploads =
+            IntGauge::new("active_uploads", "Uploads in progress").expect("valid metric");
+        let cleanup_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "cleanup_duration_seconds",
+                "Time to remove the expired files",
+            )
+            .buckets(vec![0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0]),
+        )
+        .expect("valid metric");
+        let cleanup_deleted_files =
+            IntCounter::new("cleanup_deleted_files_total", "Expired files removed")
+                .expect("valid metric");
+        let cleanup_failures =
+            IntCounter::new("cleanup_failures_total", "Failed cleanup runs").expect("valid metric");
+        let db_connections =
+            IntGauge::new("db_connections", "Open sqlite connections").expect("valid metric");
+        let db_idle_connections =
+            IntGauge::new("db_idle_connections", "Sqlite connections not in use")
+                .expect("valid metric");
+        let stored_bytes = IntGaugeVec::new(
+            Opts::new("stored_bytes", "Size of the files currently stored"),
+            &["backend"],
+        )
+        .expect("valid metric");
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 11] = [
+            Box::new(http_requests.clone()),
+            Box::new(http_duration.clone()),
+            Box::new(uploaded_bytes.clone()),
+            Box::new(downloaded_bytes.clone()),
+            Box::new(active_uploads.clone()),
+            Box::new(cleanup_duration.clone()),
+            Box::new(cleanup_deleted_files.clone()),
+            Box::new(cleanup_failures.clone()),
+            Box::new(db_connections.clone()),
+            Box::new(db_idle_connections.clone()),
+            Box::new(stored_bytes.clone()),
+        ];
+        for collector in collectors {
+            registry
+                .register(collector)
+                .expect("the metric names are unique");
+        }
+
+        Self {
+            registry,
+            http_requests,
+            http_duration,
+            uploaded_bytes,
+            downloaded_bytes,
+            active_uploads,
+            cleanup_duration,
+            cleanup_deleted_files,
+            cleanup_failures,
+            db_connections,
+            db_idle_connections,
+            stored_bytes,
+        }
+    }
+
+    pub(crate) fn add_uploaded_bytes(&self, backend: &str, bytes: u64) {
+        self.uploaded_bytes
+            .with_label_values(&[backend])
+            .inc_by(bytes);
+    }
+
+    /// The text format for prometheus, with the database gauges refreshed
+    pub(crate) async fn render(&self, state: &AppState) -> Result<String> {
+        let (connections, idle) = state.db.pool_stats();
+        self.db_connections.set(connections as i64);
+        self.db_idle_connections.set(idle as i64);
+
+        // backends without any file left must not keep their last value
+        self.stored_bytes.reset();
+        for (backend, bytes) in state.db.stored_bytes_per_backend().await? {
+            self.stored_bytes.with_label_values(&[&backend]).set(bytes);
+        }
+
+        let mut out = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut out)
+            .map_err(std::io::Error::other)?;
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+}
+
+/// Count the requests and their duration per route. The route is the pattern,
+/// like `/f/:path`, to keep the number of series bounded.
+pub(crate) async fn track_requests<B>(req: Request<B>, next: Next<B>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = req.method().to_string();
+    let start = Instant::now();
+
+    let response = next.run(req).await;
+
+    let metrics = metrics();
+    metrics
+        .http_duration
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    metrics
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    response
+}
+
+/// Counts an upload as active until dropped, whatever the outcome of the upload.
+#[derive(Debug)]
+pub(crate) struct ActiveUpload(());
+
+impl ActiveUpload {
+    pub(crate) fn start() -> Self {
+        metrics().active_uploads.inc();
+        Self(())
+    }
+}
+
+impl Drop for ActiveUpload {
+    fn drop(&mut self) {
+        metrics().active_uploads.dec();
+    }
+}
+
+/// Adds the bytes read from the blob to the downloaded bytes of its backend.
+#[pin_project::pin_project]
+pub(crate) struct CountDownload<R> {
+    #[pin]
+    inner: R,
+    counter: IntCounter,
+}
+
+pub(crate) fn count_download<R: AsyncRead>(backend: &str, inner: R) -> CountDownload<R> {
+    CountDownload {
+        inner,
+        counter: metrics().downloaded_bytes.with_label_values(&[backend]),
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for CountDownload<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let res = this.inner.poll_read(cx, buf);
+        this.counter.inc_by((buf.filled().len() - before) as u64);
+        res
+    }
+}
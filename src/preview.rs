@@ -0,0 +1,170 @@
+//! Text files (logs, configs, source code…) rendered as html, with syntax highlighting.
+
+use std::sync::OnceLock;
+
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    html::{styled_line_to_highlighted_html, IncludeBackground},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+/// only the beginning of big files is shown, highlighting is expensive and
+/// browsers don't cope well with huge pages anyway.
+pub(crate) const MAX_PREVIEW_BYTES: usize = 256 * 1024;
+
+const THEME: &str = "InspiredGitHub";
+
+/// mime types which aren't under text/ but are still meant to be read by humans
+const TEXTUAL_MIME_TYPES: [&str; 8] = [
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "application/x-javascript",
+    "application/x-yaml",
+    "application/yaml",
+    "application/toml",
+    "application/x-sh",
+];
+
+/// extensions of files worth a preview whatever their mime type, since browsers send
+/// whatever they want for source files. Kept apart from syntect so that listing
+/// files doesn't load the syntax definitions.
+#[rustfmt::skip]
+const TEXTUAL_EXTENSIONS: &[&str] = &[
+    "bash", "bat", "c", "cc", "cfg", "clj", "cmake", "conf", "cpp", "cs", "css", "csv",
+    "d", "diff", "dockerfile", "el", "erl", "ex", "exs", "go", "gradle", "h", "hpp",
+    "hs", "htm", "html", "ini", "java", "js", "json", "jsx", "kt", "lisp", "log", "lua",
+    "m", "makefile", "markdown", "md", "ml", "nix", "patch", "php", "pl", "properties",
+    "ps1", "py", "r", "rb", "rs", "rst", "sass", "scala", "scss", "sh", "sql", "svelte",
+    "swift", "tex", "toml", "ts", "tsv", "tsx", "txt", "vue", "xml", "yaml", "yml", "zsh",
+];
+
+struct Highlighter {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+}
+
+/// loading the syntax definitions takes a bit of time, so only do it once,
+/// when the first preview is requested.
+fn highlighter() -> &'static Highlighter {
+    static HIGHLIGHTER: OnceLock<Highlighter> = OnceLock::new();
+    HIGHLIGHTER.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults();
+        Highlighter {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            theme: themes
+                .themes
+                .remove(THEME)
+                .expect("default theme is bundled with syntect"),
+        }
+    })
+}
+
+fn extension(file_name: Option<&str>) -> Option<&str> {
+    file_name
+        .and_then(|n| n.rsplit_once('.'))
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.is_empty())
+}
+
+/// whether a preview page makes sense for this file
+pub(crate) fn is_textual(mime_type: Option<&str>, file_name: Option<&str>) -> bool {
+    match mime_type {
+        Some(m) if m.starts_with("text/") => true,
+        Some(m) if TEXTUAL_MIME_TYPES.contains(&m) => true,
+        Some(m) if m.ends_with("+json") || m.ends_with("+xml") => true,
+        _ => extension(file_name).is_some_and(|ext| {
+            TEXTUAL_EXTENSIONS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(ext))
+        }),
+    }
+}
+
+pub(crate) fn is_markdown(mime_type: Option<&str>, file_name: Option<&str>) -> bool {
+    mime_type == Some("text/markdown") || matches!(extension(file_name), Some("md" | "markdown"))
+}
+
+/// Text to display, or None if the content looks binary.
+/// The bytes may have been truncated in the middle of a character, which is dropped.
+pub(crate) fn decode_text(raw: &[u8]) -> Option<String> {
+    if raw.contains(&0) {
+        return None;
+    }
+    let raw = match std::str::from_utf8(raw) {
+        Err(err) if err.error_len().is_none() => &raw[..err.valid_up_to()],
+        _ => raw,
+    };
+    Some(String::from_utf8_lossy(raw).into_owned())
+}
+
+fn find_syntax<'a>(
+    syntaxes: &'a SyntaxSet,
+    text: &str,
+    mime_type: Option<&str>,
+    file_name: Option<&str>,
+) -> &'a SyntaxReference {
+    let by_extension = || extension(file_name).and_then(|ext| syntaxes.find_syntax_by_token(ext));
+    // text/x-python, application/json, application/ld+json…
+    let by_mime = || {
+        mime_type
+            .and_then(|m| m.split_once('/'))
+            .map(|(_, sub)| sub.rsplit('+').next().unwrap_or(sub))
+            .map(|sub| sub.strip_prefix("x-").unwrap_or(sub))
+            .filter(|sub| *sub != "plain")
+            .and_then(|sub| syntaxes.find_syntax_by_token(sub))
+    };
+    // shebangs, <?xml…
+    let by_first_line = || {
+        text.lines()
+            .next()
+            .and_then(|l| syntaxes.find_syntax_by_first_line(l))
+    };
+
+    by_extension()
+        .or_else(by_mime)
+        .or_else(by_first_line)
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text())
+}
+
+/// Some text, highlighted as html, line by line
+#[derive(Debug)]
+pub(crate) struct Highlighted {
+    pub(crate) syntax: String,
+    pub(crate) lines: Vec<String>,
+    /// css color for the background, to go with the colors of the text
+    pub(crate) background: Option<String>,
+}
+
+/// This is cpu heavy, better called from a blocking task.
+pub(crate) fn highlight(
+    text: &str,
+    mime_type: Option<&str>,
+    file_name: Option<&str>,
+) -> Result<Highlighted, syntect::Error> {
+    let hl = highlighter();
+    let syntax = find_syntax(&hl.syntaxes, text, mime_type, file_name);
+    let mut highlight_lines = HighlightLines::new(syntax, &hl.theme);
+
+    let mut lines = Vec::new();
+    for line in LinesWithEndings::from(text) {
+        let ranges = highlight_lines.highlight_line(line, &hl.syntaxes)?;
+        let html = styled_line_to_highlighted_html(&ranges[..], IncludeBackground::No)?;
+        // each line is its own block, the line endings would add blank lines
+        lines.push(html.replace(['\r', '\n'], ""));
+    }
+
+    let background = hl
+        .theme
+        .settings
+        .background
+        .map(|c| format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b));
+
+    Ok(Highlighted {
+        syntax: syntax.name.clone(),
+        lines,
+        background,
+    })
+}
@@ -0,0 +1,83 @@
+use password_hash::rand_core::{OsRng, RngCore};
+
+/// Characters used for random paths. Lowercase only, and without the
+/// characters easily mistaken for one another (0/o, 1/l/i)
+const ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+
+/// 20 characters out of 31 possible gives a bit more than 99 bits of entropy
+const RANDOM_LEN: usize = 20;
+
+/// 7 words out of 256 gives 56 bits of entropy, which is plenty for something
+/// that expires and cannot be enumerated offline, while still being readable
+/// over the phone.
+const WORD_COUNT: usize = 7;
+
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adobe", "agent", "alarm", "album", "alley", "amber", "angle",
+    "ankle", "apple", "april", "apron", "arena", "arrow", "aspen", "atlas", "attic", "audio",
+    "autumn", "avocado", "bacon", "badge", "bagel", "baker", "bamboo", "banjo", "barn", "basil",
+    "beach", "beard", "bench", "berry", "bison", "blade", "blaze", "bloom", "board", "bonus",
+    "boxer", "brain", "brass", "bread", "brick", "broom", "brush", "bucket", "bugle", "cabin",
+    "cable", "cactus", "camel", "candle", "canoe", "canyon", "cargo", "carrot", "castle", "cedar",
+    "chalk", "cherry", "chess", "chief", "cider", "cinema", "circus", "citrus", "clay", "cliff",
+    "cloud", "clover", "cobra", "cocoa", "comet", "coral", "cotton", "crane", "crater", "crayon",
+    "cricket", "crow", "crown", "cube", "dance", "delta", "denim", "desert", "diary", "dingo",
+    "disco", "dolphin", "donkey", "dragon", "drum", "eagle", "earth", "easel", "echo", "eclipse",
+    "elbow", "elder", "ember", "engine", "falcon", "feather", "fern", "ferry", "fiddle", "flame",
+    "flute", "forest", "fossil", "fox", "frost", "fudge", "galaxy", "garden", "garlic", "gecko",
+    "geyser", "ginger", "glacier", "globe", "goose", "grape", "gravel", "guitar", "hammer",
+    "harbor", "hazel", "hedge", "helmet", "heron", "honey", "hornet", "igloo", "island", "ivory",
+    "jacket", "jaguar", "jelly", "jewel", "jungle", "kayak", "kettle", "kiwi", "koala", "ladder",
+    "lagoon", "lantern", "lemon", "lily", "lizard", "llama", "lobster", "lotus", "magnet", "mango",
+    "maple", "marble", "meadow", "melon", "mirror", "mitten", "monkey", "moose", "mosaic",
+    "muffin", "nectar", "needle", "nickel", "noodle", "nutmeg", "oasis", "ocean", "olive", "onion",
+    "opera", "orbit", "orchid", "otter", "oyster", "paddle", "panda", "papaya", "parrot", "peach",
+    "pebble", "pelican", "pepper", "piano", "pickle", "pigeon", "pilot", "pine", "planet", "plum",
+    "pollen", "pony", "poppy", "prism", "puffin", "pumpkin", "quartz", "quill", "rabbit", "radar",
+    "radish", "raven", "reef", "ribbon", "river", "robin", "rocket", "saddle", "salmon", "sandal",
+    "saturn", "scarf", "sequoia", "shadow", "shell", "sierra", "silver", "sketch", "sloth",
+    "spider", "sponge", "spruce", "squid", "statue", "summit", "sunset", "swan", "tablet", "tango",
+    "teapot", "thistle", "thunder", "tiger", "timber", "tomato", "topaz", "tulip", "tundra",
+    "turtle", "umbrella", "valley", "velvet", "violin", "walnut", "walrus", "willow", "yogurt",
+    "zebra",
+];
+
+/// How a path for a new token should be generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlugKind {
+    /// random characters, short but not easy to read out loud
+    Random,
+    /// dash separated words from a fixed list
+    Words,
+}
+
+/// Generate an unguessable path for a token, optionally starting with
+/// a human chosen prefix.
+pub fn random_slug(kind: SlugKind, prefix: Option<&str>) -> String {
+    let slug = match kind {
+        SlugKind::Random => (0..RANDOM_LEN)
+            .map(|_| ALPHABET[uniform(ALPHABET.len() as u32) as usize] as char)
+            .collect::<String>(),
+        SlugKind::Words => (0..WORD_COUNT)
+            .map(|_| WORDS[uniform(WORDS.len() as u32) as usize])
+            .collect::<Vec<_>>()
+            .join("-"),
+    };
+
+    match prefix.map(str::trim) {
+        Some(p) if !p.is_empty() => format!("{p}-{slug}"),
+        _ => slug,
+    }
+}
+
+/// uniformly distributed integer in [0, upper), using rejection sampling to avoid
+/// the modulo bias.
+fn uniform(upper: u32) -> u32 {
+    let zone = u32::MAX - (u32::MAX % upper);
+    loop {
+        let x = OsRng.next_u32();
+        if x < zone {
+            return x % upper;
+        }
+    }
+}
@@ -1,12 +1,16 @@
 use axum::extract::FromRef;
 use parking_lot::RwLock;
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 use tera::Tera;
+use tokio::sync::Semaphore;
 
 use crate::{
+    assets::{self, StaticAssets},
+    config::{Config, MetricsConfig},
     db::DBService,
     error::{AppError, Result},
-    filters::humanize_size,
+    fetch::{self, HttpClient},
+    keys::SigningKeys,
     upload::{GarageUploader, LocalFsUploader, StorageBackend},
 };
 
@@ -16,33 +20,93 @@ pub struct AppState {
     pub base_url: String,
     pub db: DBService,
     pub(crate) flash_config: axum_flash::Config,
+    pub(crate) signing_keys: Arc<SigningKeys>,
     pub storage_fs: LocalFsUploader,
     pub garage: GarageUploader,
+    /// decoding images can take a lot of memory, so only do a few at once
+    pub(crate) thumbnail_permits: Arc<Semaphore>,
+    /// whether new tokens remove the metadata from pictures, unless unchecked
+    pub strip_metadata_default: bool,
+    /// to mirror remote files
+    pub(crate) http_client: HttpClient,
+    /// for the links without a size limit, a remote file can be anything
+    pub(crate) fetch_max_size_mib: i64,
+    pub(crate) static_assets: Arc<RwLock<StaticAssets>>,
+    /// where the templates and static files replacing the embedded ones are
+    pub(crate) assets_dir: Option<PathBuf>,
+    /// reload the assets when they change, and don't let the browsers cache them
+    pub dev: bool,
+    pub metrics: MetricsConfig,
 }
 
 impl AppState {
-    pub async fn new(
-        template_path: &str,
-        db_path: &str,
-        storage_path: &str,
-        base_url: String,
-    ) -> Result<Self> {
-        let mut tera = Tera::new(template_path)?;
-        tera.register_filter("humanize_size", humanize_size);
-        let db = DBService::new(db_path).await?;
-        let flash_config = axum_flash::Config::new(axum_flash::Key::generate());
-        let garage = GarageUploader::new().await?;
+    pub async fn new(config: &Config) -> Result<Self> {
+        // in dev mode, the templates being edited are the ones of the repository
+        let assets_dir = match &config.server.assets_dir {
+            Some(dir) => Some(dir.clone()),
+            None if config.server.dev => Some(PathBuf::from(".")),
+            None => None,
+        };
+        let static_assets = StaticAssets::load(assets_dir.as_deref())
+            .map_err(|err| AppError::ConfigError(format!("cannot read the static files: {err}")))?;
+        let static_assets = Arc::new(RwLock::new(static_assets));
+        let tera = assets::load_templates(assets_dir.as_deref(), static_assets.clone())?;
+        let db = DBService::new(&config.storage.sqlite_path.to_string_lossy()).await?;
+        let signing_keys =
+            SigningKeys::load(config).map_err(|err| AppError::ConfigError(err.to_string()))?;
+        let flash_config = axum_flash::Config::new(signing_keys.current.clone());
+        let garage = GarageUploader::new(&config.storage.s3).await?;
 
         Ok(Self {
             templates: Arc::new(RwLock::new(tera)),
-            base_url,
+            base_url: config.server.base_url.clone(),
             db,
             flash_config,
-            storage_fs: LocalFsUploader::new(storage_path),
+            signing_keys: Arc::new(signing_keys),
+            storage_fs: LocalFsUploader::new(&config.storage.local_path),
             garage,
+            thumbnail_permits: Arc::new(Semaphore::new(config.limits.thumbnail_concurrency)),
+            strip_metadata_default: config.limits.strip_metadata_default,
+            http_client: fetch::client(),
+            fetch_max_size_mib: config.limits.fetch_max_size_mib,
+            static_assets,
+            assets_dir,
+            dev: config.server.dev,
+            metrics: config.metrics.clone(),
         })
     }
 
+    /// Read the templates and static files again. The current templates are
+    /// kept if the new ones are invalid, to fix them without restarting.
+    pub(crate) fn reload_assets(&self) {
+        let dir = self.assets_dir.as_deref();
+        match StaticAssets::load(dir) {
+            Ok(assets) => *self.static_assets.write() = assets,
+            Err(err) => tracing::error!("Cannot reload the static files: {err}"),
+        }
+        match assets::load_templates(dir, self.static_assets.clone()) {
+            Ok(tera) => {
+                *self.templates.write() = tera;
+                tracing::info!("Templates reloaded");
+            }
+            Err(err) => tracing::error!("Cannot reload the templates: {err:?}"),
+        }
+    }
+
+    /// the storage backend for the given type, as stored in the DB
+    pub(crate) fn get_backend(
+        &self,
+        backend_type: &str,
+    ) -> Option<Box<dyn StorageBackend + Send + Sync>> {
+        if backend_type == self.storage_fs.get_type() {
+            Some(Box::new(self.storage_fs.clone()))
+        } else if backend_type == self.garage.get_type() {
+            Some(Box::new(self.garage.clone()))
+        } else {
+            None
+        }
+    }
+
     pub async fn get_blob(
         &self,
         backend_type: &str,
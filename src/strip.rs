@@ -0,0 +1,620 @@
+//! Removes the GPS coordinates, camera model and other metadata from jpeg and png
+//! files while they are uploaded. Other formats, like the heic pictures of iPhones,
+//! are stored untouched.
+
+use exif::{In, Tag};
+
+/// identifier for the unmodified upload, stored as a derived file
+pub(crate) const ORIGINAL_KIND: &str = "original";
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// jpeg markers
+const SOS: u8 = 0xDA;
+const EOI: u8 = 0xD9;
+const APP1: u8 = 0xE1;
+const APP2: u8 = 0xE2;
+const APP13: u8 = 0xED;
+const COM: u8 = 0xFE;
+
+/// png chunks which only carry metadata
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+/// whether the metadata can be stripped from a file of this type
+pub(crate) fn can_strip(mime_type: &str) -> bool {
+    matches!(mime_type, "image/jpeg" | "image/png")
+}
+
+#[derive(Debug)]
+enum State {
+    /// the first bytes haven't been seen yet
+    Start,
+    /// not something we know how to clean
+    Passthrough,
+    /// after the end of the jpeg image: phones put other images there (depth map,
+    /// previews), with their own metadata
+    Trailer,
+    PngSignature,
+    PngChunkHeader,
+    PngChunk {
+        remaining: usize,
+        keep: bool,
+    },
+    JpegMarker,
+    JpegLength,
+    JpegSegment {
+        remaining: usize,
+        keep: bool,
+        /// the segment is a start of scan, followed by the image data
+        scan: bool,
+    },
+    /// APP2 has the color profile, but also the index of the trailing images
+    JpegApp2 {
+        len: usize,
+    },
+    /// image data, up to the next marker which isn't a restart
+    JpegScan,
+    /// the exif segment is read entirely to only keep the harmless parts
+    JpegExif {
+        len: usize,
+    },
+}
+
+/// Fed with the chunks of an upload, returns the bytes to store, without the
+/// metadata segments.
+/// For jpeg, the exif data is replaced with a minimal one, with only the orientation
+/// (otherwise pictures from phones are displayed sideways) and the capture time.
+#[derive(Debug)]
+pub(crate) struct MetadataStripper {
+    state: State,
+    /// header being read, when it is split across chunks
+    buf: Vec<u8>,
+}
+
+impl MetadataStripper {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: State::Start,
+            buf: Vec::with_capacity(8),
+        }
+    }
+
+    pub(crate) fn feed(&mut self, mut input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len());
+
+        while !input.is_empty() {
+            match self.state {
+                State::Start => {
+                    if !fill(&mut self.buf, &mut input, 2) {
+                        break;
+                    }
+                    self.state = if self.buf[..] == JPEG_SOI {
+                        State::JpegMarker
+                    } else if self.buf[..] == PNG_SIGNATURE[..2] {
+                        State::PngSignature
+                    } else {
+                        State::Passthrough
+                    };
+                    if !matches!(self.state, State::PngSignature) {
+                        out.append(&mut self.buf);
+                    }
+                }
+                State::Passthrough => {
+                    out.extend_from_slice(input);
+                    input = &[];
+                }
+                State::Trailer => input = &[],
+                State::PngSignature => {
+                    if !fill(&mut self.buf, &mut input, PNG_SIGNATURE.len()) {
+                        break;
+                    }
+                    self.state = if self.buf[..] == PNG_SIGNATURE {
+                        State::PngChunkHeader
+                    } else {
+                        State::Passthrough
+                    };
+                    out.append(&mut self.buf);
+                }
+                State::PngChunkHeader => {
+                    // 4 bytes of length, 4 bytes of chunk type
+                    if !fill(&mut self.buf, &mut input, 8) {
+                        break;
+                    }
+                    let len = u32::from_be_bytes(self.buf[..4].try_into().unwrap()) as usize;
+                    let chunk_type = &self.buf[4..8];
+                    let keep = !PNG_METADATA_CHUNKS.iter().any(|c| c[..] == *chunk_type);
+                    if chunk_type == b"IEND" {
+                        // trailing bytes, if any, aren't parsed
+                        out.append(&mut self.buf);
+                        self.state = State::Passthrough;
+                        continue;
+                    }
+                    if keep {
+                        out.append(&mut self.buf);
+                    } else {
+                        self.buf.clear();
+                    }
+                    // the crc is after the data
+                    self.state = State::PngChunk {
+                        remaining: len + 4,
+                        keep,
+                    };
+                }
+                State::PngChunk { remaining, keep } => {
+                    let remaining = copy(&mut out, &mut input, remaining, keep);
+                    self.state = if remaining == 0 {
+                        State::PngChunkHeader
+                    } else {
+                        State::PngChunk { remaining, keep }
+                    };
+                }
+                State::JpegMarker => {
+                    if !fill(&mut self.buf, &mut input, 2) {
+                        break;
+                    }
+                    if self.buf[0] != 0xFF {
+                        // not a marker, the file is corrupted, don't touch anything
+                        out.append(&mut self.buf);
+                        self.state = State::Passthrough;
+                        continue;
+                    }
+                    match self.buf[1] {
+                        // fill byte, the marker is the next byte
+                        0xFF => {
+                            out.push(0xFF);
+                            self.buf.truncate(1);
+                        }
+                        // markers without payload
+                        0x01 | 0xD0..=0xD8 => out.append(&mut self.buf),
+                        EOI => {
+                            out.append(&mut self.buf);
+                            self.state = State::Trailer;
+                        }
+                        _ => self.state = State::JpegLength,
+                    }
+                }
+                State::JpegLength => {
+                    // marker + 2 bytes of length, which include themselves
+                    if !fill(&mut self.buf, &mut input, 4) {
+                        break;
+                    }
+                    let marker = self.buf[1];
+                    let len = u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize;
+                    if len < 2 {
+                        out.append(&mut self.buf);
+                        self.state = State::Passthrough;
+                        continue;
+                    }
+                    self.state = match marker {
+                        APP1 => State::JpegExif { len: len - 2 },
+                        APP2 => State::JpegApp2 { len: len - 2 },
+                        // photoshop IPTC and comments
+                        APP13 | COM => {
+                            self.buf.clear();
+                            State::JpegSegment {
+                                remaining: len - 2,
+                                keep: false,
+                                scan: false,
+                            }
+                        }
+                        _ => {
+                            out.append(&mut self.buf);
+                            State::JpegSegment {
+                                remaining: len - 2,
+                                keep: true,
+                                scan: marker == SOS,
+                            }
+                        }
+                    };
+                }
+                State::JpegSegment {
+                    remaining,
+                    keep,
+                    scan,
+                } => {
+                    let remaining = copy(&mut out, &mut input, remaining, keep);
+                    self.state = match remaining {
+                        0 if scan => State::JpegScan,
+                        0 => State::JpegMarker,
+                        _ => State::JpegSegment {
+                            remaining,
+                            keep,
+                            scan,
+                        },
+                    };
+                }
+                State::JpegApp2 { len } => {
+                    let peeked = len.min(4);
+                    if !fill(&mut self.buf, &mut input, 4 + peeked) {
+                        break;
+                    }
+                    // the images it points to are dropped
+                    let keep = self.buf[4..] != *b"MPF\0";
+                    if keep {
+                        out.append(&mut self.buf);
+                    } else {
+                        self.buf.clear();
+                    }
+                    self.state = State::JpegSegment {
+                        remaining: len - peeked,
+                        keep,
+                        scan: false,
+                    };
+                }
+                State::JpegScan => {
+                    if self.buf.is_empty() {
+                        let Some(ff) = input.iter().position(|b| *b == 0xFF) else {
+                            out.extend_from_slice(input);
+                            input = &[];
+                            continue;
+                        };
+                        out.extend_from_slice(&input[..ff]);
+                        input = &input[ff..];
+                    }
+                    if !fill(&mut self.buf, &mut input, 2) {
+                        break;
+                    }
+                    match self.buf[1] {
+                        // escaped 0xFF and restart markers are part of the data
+                        0x00 | 0xD0..=0xD7 => out.append(&mut self.buf),
+                        // fill byte
+                        0xFF => {
+                            out.push(0xFF);
+                            self.buf.truncate(1);
+                        }
+                        // the marker is handled as any other, progressive jpegs
+                        // have several scans with tables in between
+                        _ => self.state = State::JpegMarker,
+                    }
+                }
+                State::JpegExif { len } => {
+                    if !fill(&mut self.buf, &mut input, 4 + len) {
+                        break;
+                    }
+                    if let Some(segment) = self.buf[4..]
+                        .strip_prefix(b"Exif\0\0")
+                        .and_then(minimal_exif_segment)
+                    {
+                        out.extend_from_slice(&segment);
+                    }
+                    // APP1 is also used for XMP, which is dropped entirely
+                    self.buf.clear();
+                    self.state = State::JpegMarker;
+                }
+            }
+        }
+
+        out
+    }
+
+    /// bytes left over when the upload is truncated in the middle of a header
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        let rest = std::mem::take(&mut self.buf);
+        match std::mem::replace(&mut self.state, State::Passthrough) {
+            State::JpegExif { .. } | State::Trailer => Vec::new(),
+            _ => rest,
+        }
+    }
+}
+
+/// move bytes from the input to the buffer until it holds `len` bytes.
+/// Returns whether the buffer is complete.
+fn fill(buf: &mut Vec<u8>, input: &mut &[u8], len: usize) -> bool {
+    let n = len.saturating_sub(buf.len()).min(input.len());
+    buf.extend_from_slice(&input[..n]);
+    *input = &input[n..];
+    buf.len() >= len
+}
+
+/// consume up to `remaining` bytes of the input, and returns how many are
+/// still expected.
+fn copy(out: &mut Vec<u8>, input: &mut &[u8], remaining: usize, keep: bool) -> usize {
+    let n = remaining.min(input.len());
+    if keep {
+        out.extend_from_slice(&input[..n]);
+    }
+    *input = &input[n..];
+    remaining - n
+}
+
+/// Build an APP1 segment with only the orientation and the capture time
+/// from the given exif data, or None if there is nothing worth keeping.
+fn minimal_exif_segment(tiff: &[u8]) -> Option<Vec<u8>> {
+    let exif = exif::Reader::new().read_raw(tiff.to_vec()).ok()?;
+    let orientation = exif
+        .get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0))
+        .filter(|o| (2..=8).contains(o));
+    let captured_at = exif
+        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .and_then(|f| match &f.value {
+            exif::Value::Ascii(v) => v.first().cloned(),
+            _ => None,
+        })
+        .filter(|v| v.len() == 19);
+
+    if orientation.is_none() && captured_at.is_none() {
+        return None;
+    }
+
+    // little endian tiff, with IFD0 right after the header
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend_from_slice(&8u32.to_le_bytes());
+
+    let ifd0_entries = orientation.is_some() as u16 + captured_at.is_some() as u16;
+    let ifd0_len = 2 + 12 * ifd0_entries as u32 + 4;
+    let exif_ifd_offset = 8 + ifd0_len;
+    // a single entry, pointing to the date right after it
+    let date_offset = exif_ifd_offset + 2 + 12 + 4;
+
+    tiff.extend_from_slice(&ifd0_entries.to_le_bytes());
+    if let Some(o) = orientation {
+        // SHORT, 1 value, stored inline
+        tiff.extend_from_slice(&0x0112u16.to_le_bytes());
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&(o as u16).to_le_bytes());
+        tiff.extend_from_slice(&[0, 0]);
+    }
+    if captured_at.is_some() {
+        // pointer to the exif IFD, LONG
+        tiff.extend_from_slice(&0x8769u16.to_le_bytes());
+        tiff.extend_from_slice(&4u16.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&exif_ifd_offset.to_le_bytes());
+    }
+    // no next IFD
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+
+    if let Some(date) = captured_at {
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        // DateTimeOriginal, ASCII, 20 bytes with the trailing nul
+        tiff.extend_from_slice(&0x9003u16.to_le_bytes());
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        tiff.extend_from_slice(&20u32.to_le_bytes());
+        tiff.extend_from_slice(&date_offset.to_le_bytes());
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(&date);
+        tiff.push(0);
+    }
+
+    let len = 2 + 6 + tiff.len();
+    let mut segment = vec![0xFF, APP1];
+    segment.extend_from_slice(&(len as u16).to_be_bytes());
+    segment.extend_from_slice(b"Exif\0\0");
+    segment.extend_from_slice(&tiff);
+    Some(segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageOutputFormat, RgbImage};
+
+    use super::*;
+
+    /// the sizes of the chunks the inputs are split into, to cut the headers
+    /// and segments in every possible way
+    const CHUNK_SIZES: [usize; 6] = [1, 2, 3, 7, 64, usize::MAX];
+
+    fn strip(input: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut stripper = MetadataStripper::new();
+        let mut out = Vec::new();
+        for chunk in input.chunks(chunk_size.min(input.len()).max(1)) {
+            out.extend(stripper.feed(chunk));
+        }
+        out.extend(stripper.finish());
+        out
+    }
+
+    fn encoded(format: ImageOutputFormat) -> Vec<u8> {
+        let img = RgbImage::from_fn(32, 24, |x, y| image::Rgb([x as u8 * 8, y as u8 * 10, 128]));
+        let mut out = Cursor::new(Vec::new());
+        img.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    /// little endian tiff with the orientation, a camera make, the capture
+    /// time in the exif IFD and a latitude in the GPS IFD
+    fn tiff_with_gps() -> Vec<u8> {
+        let entry = |tiff: &mut Vec<u8>, tag: u16, typ: u16, count: u32, value: u32| {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&typ.to_le_bytes());
+            tiff.extend_from_slice(&count.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+        };
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        // IFD0 at 8, 4 entries until 62, then the make, the exif IFD at 68,
+        // the date at 86 and the GPS IFD at 106
+        tiff.extend_from_slice(&4u16.to_le_bytes());
+        entry(&mut tiff, 0x010F, 2, 6, 62);
+        entry(&mut tiff, 0x0112, 3, 1, 6);
+        entry(&mut tiff, 0x8769, 4, 1, 68);
+        entry(&mut tiff, 0x8825, 4, 1, 106);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(b"Phone\0");
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        entry(&mut tiff, 0x9003, 2, 20, 86);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(b"2023:07:14 18:32:05\0");
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        entry(&mut tiff, 0x0001, 2, 2, u32::from_le_bytes(*b"N\0\0\0"));
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        assert_eq!(tiff.len(), 124);
+        tiff
+    }
+
+    /// a jpeg from a phone: exif with GPS, an xmp packet, a color profile,
+    /// IPTC, a comment, and a second image after the end of the first one
+    fn phone_jpeg() -> (Vec<u8>, Vec<u8>) {
+        let plain = encoded(ImageOutputFormat::Jpeg(90));
+        let exif = [&b"Exif\0\0"[..], &tiff_with_gps()].concat();
+        let mut jpeg = plain[..2].to_vec();
+        jpeg.extend(jpeg_segment(APP1, &exif));
+        jpeg.extend(jpeg_segment(
+            APP1,
+            b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>",
+        ));
+        jpeg.extend(jpeg_segment(APP2, b"ICC_PROFILE\0\x01\x01profile"));
+        jpeg.extend(jpeg_segment(APP2, b"MPF\0II*\0index of the images"));
+        jpeg.extend(jpeg_segment(APP13, b"Photoshop 3.0\0IPTC"));
+        jpeg.extend(jpeg_segment(COM, b"taken at home"));
+        jpeg.extend_from_slice(&plain[2..]);
+        // the depth map, with its own metadata
+        jpeg.extend_from_slice(&plain[..2]);
+        jpeg.extend(jpeg_segment(APP1, &exif));
+        jpeg.extend_from_slice(&plain[2..]);
+        (jpeg, plain)
+    }
+
+    /// the markers of the segments before the image data
+    fn jpeg_markers(jpeg: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut segments = Vec::new();
+        let mut at = 2;
+        while jpeg[at + 1] != SOS {
+            let len = u16::from_be_bytes([jpeg[at + 2], jpeg[at + 3]]) as usize;
+            segments.push((jpeg[at + 1], jpeg[at + 4..at + 2 + len].to_vec()));
+            at += 2 + len;
+        }
+        segments
+    }
+
+    #[test]
+    fn jpeg_metadata_removed() {
+        let (jpeg, plain) = phone_jpeg();
+        for chunk_size in CHUNK_SIZES {
+            let stripped = strip(&jpeg, chunk_size);
+
+            let markers = jpeg_markers(&stripped);
+            let app = |marker| markers.iter().filter(move |(m, _)| *m == marker);
+            assert_eq!(app(APP1).count(), 1, "{chunk_size}");
+            assert_eq!(app(APP13).count(), 0, "{chunk_size}");
+            assert_eq!(app(COM).count(), 0, "{chunk_size}");
+            let app2: Vec<_> = app(APP2).collect();
+            assert_eq!(app2.len(), 1, "{chunk_size}");
+            assert!(app2[0].1.starts_with(b"ICC_PROFILE\0"), "{chunk_size}");
+
+            // only the first image, with the data untouched
+            assert!(stripped.ends_with(&plain[2..]), "{chunk_size}");
+            let soi_count = stripped.windows(2).filter(|w| *w == JPEG_SOI).count();
+            assert_eq!(soi_count, 1, "{chunk_size}");
+            let decoded = image::load_from_memory(&stripped).unwrap();
+            let expected = image::load_from_memory(&plain).unwrap();
+            assert_eq!(decoded.as_bytes(), expected.as_bytes(), "{chunk_size}");
+        }
+    }
+
+    #[test]
+    fn jpeg_exif_rebuilt() {
+        let (jpeg, _) = phone_jpeg();
+        for chunk_size in CHUNK_SIZES {
+            let stripped = strip(&jpeg, chunk_size);
+            let exif = exif::Reader::new()
+                .read_from_container(&mut Cursor::new(&stripped))
+                .unwrap();
+
+            let orientation = exif.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+            assert_eq!(orientation.value.get_uint(0), Some(6), "{chunk_size}");
+            let date = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).unwrap();
+            assert_eq!(
+                date.display_value().to_string(),
+                "2023-07-14 18:32:05",
+                "{chunk_size}"
+            );
+            // nothing else, like the make or the GPS IFD
+            let tags: Vec<_> = exif.fields().map(|f| f.tag).collect();
+            assert_eq!(tags, [Tag::Orientation, Tag::DateTimeOriginal]);
+        }
+    }
+
+    #[test]
+    fn jpeg_scan_markers() {
+        // restart markers, an escaped 0xFF and fill bytes in the image data,
+        // then a second scan like in progressive jpegs
+        let mut jpeg = JPEG_SOI.to_vec();
+        jpeg.extend(jpeg_segment(SOS, &[1, 2, 3]));
+        jpeg.extend_from_slice(&[0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56, 0xFF, 0xFF, 0xD1]);
+        jpeg.extend(jpeg_segment(0xC4, &[4, 5]));
+        jpeg.extend(jpeg_segment(SOS, &[6]));
+        jpeg.extend_from_slice(&[0x78, 0xFF, 0xD9]);
+        let mut with_comment = jpeg.clone();
+        with_comment.extend(jpeg_segment(COM, b"after the end"));
+
+        for chunk_size in CHUNK_SIZES {
+            assert_eq!(strip(&with_comment, chunk_size), jpeg, "{chunk_size}");
+        }
+    }
+
+    /// the chunk types of a png, in order
+    fn png_chunks(png: &[u8]) -> Vec<[u8; 4]> {
+        let mut chunks = Vec::new();
+        let mut at = PNG_SIGNATURE.len();
+        while at < png.len() {
+            let len = u32::from_be_bytes(png[at..at + 4].try_into().unwrap()) as usize;
+            chunks.push(png[at + 4..at + 8].try_into().unwrap());
+            at += 12 + len;
+        }
+        chunks
+    }
+
+    fn png_chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(data);
+        let crc = crc32fast::hash(&chunk[4..]);
+        chunk.extend_from_slice(&crc.to_be_bytes());
+        chunk
+    }
+
+    #[test]
+    fn png_metadata_removed() {
+        let plain = encoded(ImageOutputFormat::Png);
+        // after the signature and IHDR
+        let ihdr_end = PNG_SIGNATURE.len() + 12 + 13;
+        let mut png = plain[..ihdr_end].to_vec();
+        png.extend(png_chunk(b"tEXt", b"Comment\0at home"));
+        png.extend(png_chunk(
+            b"iTXt",
+            b"XML:com.adobe.xmp\0\0\0\0\0<x:xmpmeta/>",
+        ));
+        png.extend(png_chunk(b"zTXt", b"Author\0\0compressed"));
+        png.extend(png_chunk(b"eXIf", &tiff_with_gps()));
+        png.extend(png_chunk(b"tIME", &[7, 231, 7, 14, 18, 32, 5]));
+        png.extend_from_slice(&plain[ihdr_end..]);
+
+        for chunk_size in CHUNK_SIZES {
+            let stripped = strip(&png, chunk_size);
+            assert_eq!(stripped, plain, "{chunk_size}");
+            assert_eq!(png_chunks(&stripped), png_chunks(&plain));
+        }
+    }
+
+    #[test]
+    fn other_files_untouched() {
+        let truncated_png = &encoded(ImageOutputFormat::Png)[..5];
+        let truncated_jpeg_header = [0xFF, 0xD8, 0xFF];
+        let truncated_segment = [0xFF, 0xD8, 0xFF, 0xDB, 0x00, 0x43, 1, 2, 3];
+        let inputs: [&[u8]; 5] = [
+            b"just some text, not a picture",
+            b"\xFF",
+            truncated_png,
+            &truncated_jpeg_header,
+            &truncated_segment,
+        ];
+        for input in inputs {
+            for chunk_size in CHUNK_SIZES {
+                assert_eq!(strip(input, chunk_size), input, "{input:?} {chunk_size}");
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Headers for tar archives (ustar, with pax extensions for long names and
+//! big files). The size of every file must be known up front, so the length
+//! of the whole archive can be computed before writing anything.
+
+const BLOCK_SIZE: u64 = 512;
+
+/// two empty blocks mark the end of the archive
+pub(crate) const END_OF_ARCHIVE: [u8; 2 * BLOCK_SIZE as usize] = [0; 2 * BLOCK_SIZE as usize];
+
+/// the name field of the ustar header
+const MAX_NAME_LEN: usize = 100;
+/// biggest size which fits in the 11 octal digits of the header (8 GiB)
+const MAX_USTAR_SIZE: u64 = 0o77777777777;
+
+#[derive(Debug)]
+pub(crate) struct TarEntry {
+    pub(crate) name: String,
+    pub(crate) size: u64,
+    /// unix timestamp, in seconds
+    pub(crate) mtime: i64,
+}
+
+impl TarEntry {
+    /// the header blocks, including the pax header if needed
+    pub(crate) fn header(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        if self.name.len() > MAX_NAME_LEN || !self.name.is_ascii() {
+            records.extend(pax_record("path", &self.name));
+        }
+        if self.size > MAX_USTAR_SIZE {
+            records.extend(pax_record("size", &self.size.to_string()));
+        }
+
+        let mut header = Vec::new();
+        if !records.is_empty() {
+            let pax_name = format!("PaxHeaders/{}", truncate(&self.name, MAX_NAME_LEN - 11));
+            header.extend_from_slice(&ustar_block(
+                &pax_name,
+                records.len() as u64,
+                self.mtime,
+                b'x',
+            ));
+            let len = records.len() as u64;
+            header.extend(records);
+            header.resize(header.len() + padding(len), 0);
+        }
+        header.extend_from_slice(&ustar_block(
+            truncate(&self.name, MAX_NAME_LEN),
+            self.size.min(MAX_USTAR_SIZE),
+            self.mtime,
+            b'0',
+        ));
+        header
+    }
+
+    /// number of bytes taken by this file in the archive
+    pub(crate) fn archive_len(&self) -> u64 {
+        self.header().len() as u64 + self.size + padding(self.size) as u64
+    }
+}
+
+/// zeroes to add after the content of a file to complete its last block
+pub(crate) fn padding(size: u64) -> usize {
+    ((BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE) as usize
+}
+
+/// Length of the archive with all these files
+pub(crate) fn archive_len<'a>(entries: impl IntoIterator<Item = &'a TarEntry>) -> u64 {
+    entries.into_iter().map(TarEntry::archive_len).sum::<u64>() + END_OF_ARCHIVE.len() as u64
+}
+
+fn ustar_block(name: &str, size: u64, mtime: i64, typeflag: u8) -> [u8; BLOCK_SIZE as usize] {
+    let mut block = [0u8; BLOCK_SIZE as usize];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut block[100..108], 0o644);
+    // uid and gid
+    write_octal(&mut block[108..116], 0);
+    write_octal(&mut block[116..124], 0);
+    write_octal(&mut block[124..136], size);
+    write_octal(&mut block[136..148], mtime.max(0) as u64);
+    block[156] = typeflag;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    // computed with the checksum field filled with spaces
+    block[148..156].fill(b' ');
+    let checksum: u64 = block.iter().map(|b| *b as u64).sum();
+    write_octal(&mut block[148..155], checksum);
+    block
+}
+
+/// zero padded octal number, followed by a nul byte
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let s = format!("{value:0digits$o}");
+    field[..digits].copy_from_slice(&s.as_bytes()[s.len() - digits..]);
+    field[digits] = 0;
+}
+
+/// "<len> <key>=<value>\n", where the length includes itself
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + base.to_string().len();
+    // adding the length may add a digit to it
+    if len.to_string().len() != base.to_string().len() {
+        len = base + len.to_string().len();
+    }
+    format!("{len} {key}={value}\n").into_bytes()
+}
+
+/// at most `max` bytes, without splitting a character
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the value of an octal field, up to its nul byte
+    fn read_octal(field: &[u8]) -> u64 {
+        let digits = field.split(|b| *b == 0).next().unwrap();
+        u64::from_str_radix(std::str::from_utf8(digits).unwrap(), 8).unwrap()
+    }
+
+    fn check_block(block: &[u8], name: &str, size: u64, typeflag: u8) {
+        assert_eq!(block.len(), BLOCK_SIZE as usize);
+        assert_eq!(
+            block[..100].split(|b| *b == 0).next().unwrap(),
+            name.as_bytes()
+        );
+        assert_eq!(read_octal(&block[124..136]), size);
+        assert_eq!(block[156], typeflag);
+        assert_eq!(&block[257..265], b"ustar\x0000");
+
+        let mut unsigned = block.to_vec();
+        unsigned[148..156].fill(b' ');
+        let checksum: u64 = unsigned.iter().map(|b| *b as u64).sum();
+        assert_eq!(read_octal(&block[148..156]), checksum);
+    }
+
+    #[test]
+    fn block_padding() {
+        assert_eq!(padding(0), 0);
+        assert_eq!(padding(1), 511);
+        assert_eq!(padding(511), 1);
+        assert_eq!(padding(512), 0);
+        assert_eq!(padding(513), 511);
+    }
+
+    #[test]
+    fn pax_record_length() {
+        // around the lengths where the length gets another digit
+        for value_len in 0..1100 {
+            let record = pax_record("path", &"a".repeat(value_len));
+            let text = std::str::from_utf8(&record).unwrap();
+            let (len, _) = text.split_once(' ').unwrap();
+            assert_eq!(len.parse::<usize>().unwrap(), record.len(), "{text}");
+            assert!(text.ends_with('\n'));
+        }
+    }
+
+    #[test]
+    fn short_name() {
+        let entry = TarEntry {
+            name: "notes.txt".to_string(),
+            size: 1234,
+            mtime: 1_700_000_000,
+        };
+        let header = entry.header();
+
+        check_block(&header, "notes.txt", 1234, b'0');
+        assert_eq!(read_octal(&header[136..148]), 1_700_000_000);
+    }
+
+    #[test]
+    fn long_name() {
+        let name = format!("{}é.txt", "x".repeat(120));
+        let entry = TarEntry {
+            name: name.clone(),
+            size: 10,
+            mtime: 0,
+        };
+        let header = entry.header();
+        let records = pax_record("path", &name);
+
+        assert_eq!(header.len(), 3 * BLOCK_SIZE as usize);
+        let pax_name = format!("PaxHeaders/{}", truncate(&name, MAX_NAME_LEN - 11));
+        check_block(&header[..512], &pax_name, records.len() as u64, b'x');
+        assert_eq!(&header[512..512 + records.len()], &records[..]);
+        assert!(header[512 + records.len()..1024].iter().all(|b| *b == 0));
+        check_block(&header[1024..], truncate(&name, MAX_NAME_LEN), 10, b'0');
+    }
+
+    #[test]
+    fn big_file() {
+        let size = 10 * 1024 * 1024 * 1024;
+        let entry = TarEntry {
+            name: "disk.img".to_string(),
+            size,
+            mtime: 0,
+        };
+        let header = entry.header();
+        let records = pax_record("size", &size.to_string());
+
+        assert_eq!(header.len(), 3 * BLOCK_SIZE as usize);
+        check_block(
+            &header[..512],
+            "PaxHeaders/disk.img",
+            records.len() as u64,
+            b'x',
+        );
+        assert_eq!(&header[512..512 + records.len()], &records[..]);
+        // the real size is in the pax header
+        check_block(&header[1024..], "disk.img", MAX_USTAR_SIZE, b'0');
+        assert_eq!(entry.archive_len(), 3 * BLOCK_SIZE + size);
+    }
+
+    #[test]
+    fn whole_archive_len() {
+        let entries = [
+            TarEntry {
+                name: "empty".to_string(),
+                size: 0,
+                mtime: 0,
+            },
+            TarEntry {
+                name: "a".repeat(200),
+                size: 700,
+                mtime: 0,
+            },
+            TarEntry {
+                name: "block.bin".to_string(),
+                size: 1024,
+                mtime: 0,
+            },
+        ];
+
+        // written like the download of the files does
+        let mut archive = Vec::new();
+        for entry in &entries {
+            archive.extend(entry.header());
+            archive.resize(archive.len() + entry.size as usize, b'c');
+            archive.resize(archive.len() + padding(entry.size), 0);
+        }
+        archive.extend(END_OF_ARCHIVE);
+
+        assert_eq!(archive_len(&entries), archive.len() as u64);
+        assert_eq!(archive.len() % BLOCK_SIZE as usize, 0);
+    }
+}
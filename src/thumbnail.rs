@@ -0,0 +1,283 @@
+//! Thumbnails and previews of the uploaded images and pdfs, generated in the
+//! background after an upload and stored as derived files next to the original.
+//! The capture time of pictures is read at the same time, to order the galleries.
+
+use std::io::Cursor;
+
+use exif::{Exif, In, Tag};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage};
+use time::{Date, Month, OffsetDateTime, Time, UtcOffset};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::Instrument;
+
+use crate::{
+    error::{AppError, Result},
+    state::AppState,
+    upload::InitFile,
+};
+
+/// Don't attempt to decode anything bigger than that, it would take way too much memory
+const MAX_SOURCE_SIZE: u64 = 100 * 1024 * 1024;
+
+const JPEG_QUALITY: u8 = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DerivedKind {
+    /// small image, for grids and lists
+    Thumbnail,
+    /// image large enough to be displayed in a page, but much lighter than the original
+    Preview,
+}
+
+impl DerivedKind {
+    pub(crate) const ALL: [DerivedKind; 2] = [DerivedKind::Thumbnail, DerivedKind::Preview];
+
+    /// identifier stored in the DB
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            DerivedKind::Thumbnail => "thumb",
+            DerivedKind::Preview => "preview",
+        }
+    }
+
+    /// the generated image fits in a square of this size
+    fn max_dimension(&self) -> u32 {
+        match self {
+            DerivedKind::Thumbnail => 320,
+            DerivedKind::Preview => 1280,
+        }
+    }
+}
+
+/// What's needed to read a freshly uploaded file and store its derivations
+#[derive(Debug)]
+pub(crate) struct SourceFile {
+    pub(crate) file_id: i64,
+    pub(crate) token_id: i64,
+    pub(crate) token_path: String,
+    pub(crate) attempt_counter: i64,
+    pub(crate) file_index: u64,
+    pub(crate) mime_type: Option<String>,
+    pub(crate) backend_type: String,
+    pub(crate) backend_data: String,
+}
+
+/// whether thumbnails can be generated for a file of this type
+pub(crate) fn is_supported(mime_type: &str) -> bool {
+    matches!(
+        mime_type,
+        "image/jpeg" | "image/png" | "image/gif" | "image/webp" | "application/pdf"
+    )
+}
+
+/// Generate the thumbnails for the given file in the background. Failures are only
+/// logged since the original file is still there.
+pub(crate) fn spawn_generation(state: AppState, source: SourceFile) {
+    match source.mime_type.as_deref() {
+        Some(m) if is_supported(m) => (),
+        _ => return,
+    }
+
+    // still in the span of the upload, for its request id
+    let generation = async move {
+        match generate(&state, &source).await {
+            Ok(()) => tracing::info!(file_id = source.file_id, "Generated thumbnails"),
+            Err(err) => tracing::warn!(
+                file_id = source.file_id,
+                "Cannot generate thumbnails: {err:?}"
+            ),
+        }
+    };
+    tokio::spawn(generation.in_current_span());
+}
+
+async fn generate(state: &AppState, source: &SourceFile) -> Result<()> {
+    let _permit = state
+        .thumbnail_permits
+        .acquire()
+        .await
+        .map_err(|err| AppError::ThumbnailError(format!("{err:?}")))?;
+
+    let blob = state
+        .get_blob(&source.backend_type, source.backend_data.clone())
+        .await?;
+    let mut raw = Vec::new();
+    let n = blob.take(MAX_SOURCE_SIZE + 1).read_to_end(&mut raw).await?;
+    if n as u64 > MAX_SOURCE_SIZE {
+        return Err(AppError::ThumbnailError(format!(
+            "file too big to generate a thumbnail: more than {MAX_SOURCE_SIZE} bytes"
+        )));
+    }
+
+    if let Some(captured_at) = read_exif(&raw).and_then(|e| capture_time(&e)) {
+        state
+            .db
+            .set_file_captured_at(source.file_id, captured_at)
+            .await?;
+    }
+
+    let raw = if source.mime_type.as_deref() == Some("application/pdf") {
+        render_pdf_first_page(raw).await?
+    } else {
+        raw
+    };
+
+    let derived = tokio::task::spawn_blocking(move || resize_all(&raw))
+        .await
+        .map_err(|err| AppError::ThumbnailError(format!("{err:?}")))?
+        .map_err(|err| AppError::ThumbnailError(err.to_string()))?;
+
+    let backend = state
+        .get_backend(&source.backend_type)
+        .ok_or_else(|| AppError::UnknownStorageBackend(source.backend_type.clone()))?;
+    for (kind, bytes) in derived {
+        let init_file = InitFile {
+            token_id: source.token_id,
+            token_path: &source.token_path,
+            file_index: source.file_index,
+            attempt_counter: source.attempt_counter,
+            mime_type: Some("image/jpeg"),
+            file_name: None,
+            variant: Some(kind.as_str()),
+        };
+        let (mut writer, data) = backend.initiate_upload(&init_file).await?;
+        writer.write_all(&bytes).await?;
+        let data = writer.finalize_upload().await?.unwrap_or(data);
+        let created = state
+            .db
+            .create_derived_file(
+                source.file_id,
+                kind.as_str(),
+                "image/jpeg",
+                backend.get_type(),
+                data.clone(),
+            )
+            .await;
+        // the cleanup may have removed the file while this was running, and
+        // nothing else would ever delete this blob
+        match created {
+            Ok(Some(_)) => (),
+            Ok(None) => {
+                backend.delete_blob(data).await?;
+                return Ok(());
+            }
+            Err(err) => {
+                if let Err(delete_err) = backend.delete_blob(data).await {
+                    tracing::warn!(
+                        file_id = source.file_id,
+                        "Cannot delete the orphaned {} blob: {delete_err:?}",
+                        kind.as_str()
+                    );
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// decode the image once, and encode all the derived sizes as jpeg
+fn resize_all(raw: &[u8]) -> image::ImageResult<Vec<(DerivedKind, Vec<u8>)>> {
+    let img = image::load_from_memory(raw)?;
+    let orientation = read_exif(raw)
+        .and_then(|e| {
+            e.get_field(Tag::Orientation, In::PRIMARY)
+                .and_then(|f| f.value.get_uint(0))
+        })
+        .unwrap_or(1);
+    let img = apply_orientation(img, orientation);
+
+    let mut result = Vec::with_capacity(DerivedKind::ALL.len());
+    for kind in DerivedKind::ALL {
+        let max = kind.max_dimension();
+        let resized = if img.width() > max || img.height() > max {
+            img.thumbnail(max, max)
+        } else {
+            img.clone()
+        };
+
+        let mut buf = Vec::new();
+        JpegEncoder::new_with_quality(&mut buf, JPEG_QUALITY).encode_image(&resized.to_rgb8())?;
+        result.push((kind, buf));
+    }
+
+    Ok(result)
+}
+
+fn read_exif(raw: &[u8]) -> Option<Exif> {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(raw))
+        .ok()
+}
+
+/// When the picture was taken. Cameras usually don't record the timezone,
+/// in which case it's assumed to be UTC, which is good enough to order pictures.
+fn capture_time(exif: &Exif) -> Option<OffsetDateTime> {
+    let field = exif
+        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .or_else(|| exif.get_field(Tag::DateTime, In::PRIMARY))?;
+    let dt = match &field.value {
+        exif::Value::Ascii(values) => exif::DateTime::from_ascii(values.first()?).ok()?,
+        _ => return None,
+    };
+
+    let date =
+        Date::from_calendar_date(dt.year.into(), Month::try_from(dt.month).ok()?, dt.day).ok()?;
+    let time = Time::from_hms(dt.hour, dt.minute, dt.second).ok()?;
+    let offset = match dt.offset {
+        Some(minutes) => UtcOffset::from_whole_seconds(i32::from(minutes) * 60).ok()?,
+        None => UtcOffset::UTC,
+    };
+    Some(date.with_time(time).assume_offset(offset))
+}
+
+/// Phones store pictures as they come from the sensor, and put the rotation
+/// to apply in the exif metadata.
+fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Use pdftoppm (from poppler) to get an image of the first page.
+/// If it's not installed, pdfs simply don't get any thumbnail.
+async fn render_pdf_first_page(raw: Vec<u8>) -> Result<Vec<u8>> {
+    // private to this call, and removed with everything in it when dropped
+    let dir = tempfile::Builder::new().prefix("vrac-thumb-").tempdir()?;
+    let input = dir.path().join("input.pdf");
+    let output_prefix = dir.path().join("page");
+    let output = dir.path().join("page.png");
+
+    tokio::fs::write(&input, raw).await?;
+    let status = tokio::process::Command::new("pdftoppm")
+        .args(["-png", "-singlefile", "-f", "1", "-scale-to"])
+        .arg(DerivedKind::Preview.max_dimension().to_string())
+        .arg(&input)
+        .arg(&output_prefix)
+        .status()
+        .await;
+
+    match status {
+        Ok(s) if s.success() => (),
+        Ok(s) => {
+            return Err(AppError::ThumbnailError(format!(
+                "pdftoppm exited with {s}"
+            )))
+        }
+        Err(err) => {
+            return Err(AppError::ThumbnailError(format!(
+                "cannot run pdftoppm: {err}"
+            )))
+        }
+    }
+
+    Ok(tokio::fs::read(&output).await?)
+}
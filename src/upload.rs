@@ -8,13 +8,16 @@ use std::{
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::{future::FutureExt, Future};
+use password_hash::rand_core::{OsRng, RngCore};
 use pin_project::pin_project;
 use serde::{Deserialize, Serialize};
 use tokio::{
     fs::{self, File, OpenOptions},
     io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
 };
+use tracing::Instrument;
 
+use crate::config::S3Config;
 use crate::error::AppError;
 use aws_sdk_s3 as s3;
 use s3::primitives::{ByteStream, SdkBody};
@@ -31,6 +34,22 @@ pub struct InitFile<'token, 'file> {
     pub attempt_counter: i64,
     pub mime_type: Option<&'file str>,
     pub file_name: Option<&'file str>,
+    /// set when the blob is derived from an uploaded file, like a thumbnail
+    pub variant: Option<&'file str>,
+}
+
+impl<'token, 'file> InitFile<'token, 'file> {
+    /// unique identifier for the blob, usable as a file name or an object key
+    fn key(&self) -> String {
+        let key = format!(
+            "{}_{:02}_{:03}",
+            self.token_id, self.attempt_counter, self.file_index
+        );
+        match self.variant {
+            Some(v) => format!("{key}_{v}"),
+            None => key,
+        }
+    }
 }
 
 #[async_trait]
@@ -65,6 +84,9 @@ pub trait StorageBackend {
     async fn delete_blob(&self, blob_raw_data: String) -> Result<(), AppError>;
 
     async fn read_blob(&self, blob_raw_data: String) -> Result<Box<dyn ReadBlob>, AppError>;
+
+    /// Make sure the backend can be used, for the readiness check.
+    async fn check(&self) -> Result<(), AppError>;
 }
 
 pub trait BackendErrorContext<T, E> {
@@ -148,6 +170,7 @@ impl AsyncRead for LocalFsBlob {
 
 #[async_trait]
 impl Finalize for LocalFsBlob {
+    #[tracing::instrument(skip_all, fields(backend = "local_fs"))]
     async fn finalize_upload(self: Box<Self>) -> Result<Option<String>, AppError> {
         self.inner
             .sync_all()
@@ -169,15 +192,13 @@ impl StorageBackend for LocalFsUploader {
         "local_fs"
     }
 
+    #[tracing::instrument(skip_all, fields(backend = "local_fs"))]
     async fn initiate_upload(
         &self,
         init_file: &InitFile,
     ) -> Result<(Box<dyn WriteBlob>, String), AppError> {
         let mut path = self.base_path.clone();
-        path.push(format!(
-            "{}_{:02}_{:03}",
-            init_file.token_id, init_file.attempt_counter, init_file.file_index
-        ));
+        path.push(init_file.key());
 
         let file = OpenOptions::new()
             .create(true)
@@ -197,6 +218,7 @@ impl StorageBackend for LocalFsUploader {
         ))
     }
 
+    #[tracing::instrument(skip_all, fields(backend = "local_fs"))]
     async fn delete_blob(&self, blob_raw_data: String) -> Result<(), AppError> {
         let blob_data: LocalFsData = serde_json::from_str(&blob_raw_data)?;
         match fs::remove_file(&blob_data.path).await {
@@ -213,6 +235,7 @@ impl StorageBackend for LocalFsUploader {
         }
     }
 
+    #[tracing::instrument(skip_all, fields(backend = "local_fs"))]
     async fn read_blob(&self, blob_raw_data: String) -> Result<Box<dyn ReadBlob>, AppError> {
         let blob_data: LocalFsData = serde_json::from_str(&blob_raw_data)?;
         let file = fs::File::open(&blob_data.path)
@@ -223,6 +246,21 @@ impl StorageBackend for LocalFsUploader {
             path: blob_data.path,
         }))
     }
+    #[tracing::instrument(skip_all, fields(backend = "local_fs"))]
+    async fn check(&self) -> Result<(), AppError> {
+        // creating a file is what an upload needs, being able to list the directory isn't enough
+        // a name per check, concurrent checks would remove each other's file
+        let path = self
+            .base_path
+            .join(format!(".ready_check_{:016x}", OsRng.next_u64()));
+        fs::write(&path, b"ok")
+            .await
+            .with_context(|| format!("Cannot write to {:?}", &self.base_path))?;
+        fs::remove_file(&path)
+            .await
+            .with_context(|| format!("Cannot remove {:?}", &path))?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -232,11 +270,10 @@ pub struct GarageUploader {
 }
 
 impl GarageUploader {
-    pub async fn new() -> Result<Self, AppError> {
-        let endpoint_url = "http://localhost:3900";
-        let bucket = "vrac".to_string();
+    pub async fn new(config: &S3Config) -> Result<Self, AppError> {
+        let bucket = config.bucket.clone();
         let builder: s3::config::Builder = (&aws_config::from_env()
-            .endpoint_url(endpoint_url)
+            .endpoint_url(&config.endpoint)
             .load()
             .await)
             .into();
@@ -252,15 +289,13 @@ impl StorageBackend for GarageUploader {
         "garage"
     }
 
+    #[tracing::instrument(skip_all, fields(backend = "garage"))]
     async fn initiate_upload(
         &self,
         init_file: &InitFile,
     ) -> Result<(Box<dyn WriteBlob>, String), AppError> {
         let (send_chan, channel_body) = hyper::body::Body::channel();
-        let key = format!(
-            "{}_{:02}_{:03}",
-            init_file.token_id, init_file.attempt_counter, init_file.file_index
-        );
+        let key = init_file.key();
 
         let stream = ByteStream::new(SdkBody::from(channel_body));
         let request = self
@@ -271,13 +306,17 @@ impl StorageBackend for GarageUploader {
             .body(stream)
             .set_content_type(init_file.mime_type.map(str::to_string));
 
-        let send_future = request.send().map(|res| match res {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                tracing::error!("Cannot send request to garage: {err:?}");
-                Err(ErrorKind::Other.into())
-            }
-        });
+        // the bytes are sent while the file is written, until the blob is finalized
+        let send_future = request
+            .send()
+            .map(|res| match res {
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    tracing::error!("Cannot send request to garage: {err:?}");
+                    Err(ErrorKind::Other.into())
+                }
+            })
+            .instrument(tracing::info_span!("put_object", key = %key));
 
         let data = GarageData {
             bucket: self.bucket.clone(),
@@ -292,6 +331,7 @@ impl StorageBackend for GarageUploader {
         Ok((Box::new(blob), serde_json::to_string(&data)?))
     }
 
+    #[tracing::instrument(skip_all, fields(backend = "garage"))]
     async fn delete_blob(&self, blob_raw_data: String) -> Result<(), AppError> {
         tracing::trace!("deserializing for garagedata: {blob_raw_data}");
         let blob_data: GarageData = serde_json::from_str(&blob_raw_data)?;
@@ -305,6 +345,7 @@ impl StorageBackend for GarageUploader {
         Ok(())
     }
 
+    #[tracing::instrument(skip_all, fields(backend = "garage"))]
     async fn read_blob(&self, blob_raw_data: String) -> Result<Box<dyn ReadBlob>, AppError> {
         let blob_data: GarageData = serde_json::from_str(&blob_raw_data)?;
         let response = self
@@ -321,6 +362,17 @@ impl StorageBackend for GarageUploader {
 
         Ok(Box::new(blob) as _)
     }
+
+    #[tracing::instrument(skip_all, fields(backend = "garage"))]
+    async fn check(&self) -> Result<(), AppError> {
+        self.client
+            .head_bucket()
+            .bucket(&self.bucket)
+            .send()
+            .await
+            .with_context(|| format!("Cannot reach bucket {}", self.bucket))?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -413,6 +465,7 @@ impl AsyncWrite for GarageWriteBlob {
 
 #[async_trait]
 impl Finalize for GarageWriteBlob {
+    #[tracing::instrument(skip_all, fields(backend = "garage"))]
     async fn finalize_upload(mut self: Box<Self>) -> Result<Option<String>, AppError> {
         self.flush().await?;
         self.shutdown().await?;
@@ -0,0 +1,429 @@
+//! Zip archives without compression. Most of what is shared is already compressed
+//! (pictures, videos, archives…), and storing the files as they are means the length
+//! of the archive is known before reading them, so it can be sent as Content-Length.
+//! The crc of a file is only known once it has been read, so it comes in a data
+//! descriptor after the content. Zip64 records are added when the sizes or offsets
+//! don't fit in 32 bits.
+
+use time::OffsetDateTime;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+
+/// sizes and crc in a data descriptor, and utf-8 file names
+const FLAGS: u16 = 1 << 3 | 1 << 11;
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// the upper byte is the OS, 3 for unix, so that the permissions are used
+const VERSION_MADE_BY: u16 = 3 << 8 | VERSION_ZIP64;
+/// regular file, rw-r--r--
+const EXTERNAL_ATTRIBUTES: u32 = 0o100644 << 16;
+
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const TIMESTAMP_EXTRA_ID: u16 = 0x5455;
+
+/// marks a value which is in the zip64 extra field instead
+const MAX_U16: u64 = 0xFFFF;
+const MAX_U32: u64 = 0xFFFFFFFF;
+
+#[derive(Debug)]
+pub(crate) struct ZipEntry {
+    pub(crate) name: String,
+    pub(crate) size: u64,
+    pub(crate) modified_at: OffsetDateTime,
+}
+
+impl ZipEntry {
+    fn is_zip64(&self) -> bool {
+        self.size >= MAX_U32
+    }
+}
+
+/// Produces the bytes around the content of the files, keeping track of
+/// where everything is for the central directory at the end.
+#[derive(Debug, Default)]
+pub(crate) struct ZipWriter {
+    /// bytes written so far
+    offset: u64,
+    /// offset of the local header of the entry being written
+    entry_offset: u64,
+    central_directory: Vec<u8>,
+    entry_count: u64,
+}
+
+impl ZipWriter {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// to write before the content of the file
+    pub(crate) fn start_entry(&mut self, entry: &ZipEntry) -> Vec<u8> {
+        let (time, date) = dos_date_time(entry.modified_at);
+        let mut extra = Vec::new();
+        if entry.is_zip64() {
+            // the sizes are in the data descriptor, but the field has to be there
+            // for the readers to expect a zip64 descriptor
+            push_u16(&mut extra, ZIP64_EXTRA_ID);
+            push_u16(&mut extra, 16);
+            push_u64(&mut extra, 0);
+            push_u64(&mut extra, 0);
+        }
+        push_timestamp(&mut extra, entry.modified_at);
+
+        let mut header = Vec::with_capacity(30 + entry.name.len() + extra.len());
+        push_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+        push_u16(
+            &mut header,
+            if entry.is_zip64() {
+                VERSION_ZIP64
+            } else {
+                VERSION
+            },
+        );
+        push_u16(&mut header, FLAGS);
+        // stored, no compression
+        push_u16(&mut header, 0);
+        push_u16(&mut header, time);
+        push_u16(&mut header, date);
+        // crc and sizes, in the data descriptor
+        push_u32(&mut header, 0);
+        let size = if entry.is_zip64() { MAX_U32 as u32 } else { 0 };
+        push_u32(&mut header, size);
+        push_u32(&mut header, size);
+        push_u16(&mut header, entry.name.len() as u16);
+        push_u16(&mut header, extra.len() as u16);
+        header.extend_from_slice(entry.name.as_bytes());
+        header.extend(extra);
+
+        self.entry_offset = self.offset;
+        self.offset += header.len() as u64;
+        header
+    }
+
+    /// to write after the content of the file, once its crc is known
+    pub(crate) fn finish_entry(&mut self, entry: &ZipEntry, crc: u32) -> Vec<u8> {
+        let mut descriptor = Vec::with_capacity(24);
+        push_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        push_u32(&mut descriptor, crc);
+        if entry.is_zip64() {
+            push_u64(&mut descriptor, entry.size);
+            push_u64(&mut descriptor, entry.size);
+        } else {
+            push_u32(&mut descriptor, entry.size as u32);
+            push_u32(&mut descriptor, entry.size as u32);
+        }
+
+        self.push_central_header(entry, crc);
+        self.offset += entry.size + descriptor.len() as u64;
+        descriptor
+    }
+
+    fn push_central_header(&mut self, entry: &ZipEntry, crc: u32) {
+        let (time, date) = dos_date_time(entry.modified_at);
+        let offset_zip64 = self.entry_offset >= MAX_U32;
+
+        // only the values which don't fit in the header, in this order
+        let mut zip64 = Vec::new();
+        if entry.is_zip64() {
+            push_u64(&mut zip64, entry.size);
+            push_u64(&mut zip64, entry.size);
+        }
+        if offset_zip64 {
+            push_u64(&mut zip64, self.entry_offset);
+        }
+        let mut extra = Vec::new();
+        if !zip64.is_empty() {
+            push_u16(&mut extra, ZIP64_EXTRA_ID);
+            push_u16(&mut extra, zip64.len() as u16);
+            extra.extend(zip64);
+        }
+        push_timestamp(&mut extra, entry.modified_at);
+
+        let cd = &mut self.central_directory;
+        push_u32(cd, CENTRAL_HEADER_SIGNATURE);
+        push_u16(cd, VERSION_MADE_BY);
+        let zip64 = entry.is_zip64() || offset_zip64;
+        push_u16(cd, if zip64 { VERSION_ZIP64 } else { VERSION });
+        push_u16(cd, FLAGS);
+        push_u16(cd, 0);
+        push_u16(cd, time);
+        push_u16(cd, date);
+        push_u32(cd, crc);
+        push_u32(cd, entry.size.min(MAX_U32) as u32);
+        push_u32(cd, entry.size.min(MAX_U32) as u32);
+        push_u16(cd, entry.name.len() as u16);
+        push_u16(cd, extra.len() as u16);
+        // comment, disk number and internal attributes
+        push_u16(cd, 0);
+        push_u16(cd, 0);
+        push_u16(cd, 0);
+        push_u32(cd, EXTERNAL_ATTRIBUTES);
+        push_u32(cd, self.entry_offset.min(MAX_U32) as u32);
+        cd.extend_from_slice(entry.name.as_bytes());
+        cd.extend(extra);
+
+        self.entry_count += 1;
+    }
+
+    /// the central directory and the end records, to write after all the files
+    pub(crate) fn finish(self) -> Vec<u8> {
+        let cd_offset = self.offset;
+        let cd_size = self.central_directory.len() as u64;
+        let mut out = self.central_directory;
+
+        let zip64 = self.entry_count >= MAX_U16 || cd_offset >= MAX_U32 || cd_size >= MAX_U32;
+        if zip64 {
+            let zip64_end_offset = cd_offset + cd_size;
+            push_u32(&mut out, ZIP64_END_SIGNATURE);
+            // size of the rest of the record
+            push_u64(&mut out, 44);
+            push_u16(&mut out, VERSION_MADE_BY);
+            push_u16(&mut out, VERSION_ZIP64);
+            // number of this disk, and of the disk with the central directory
+            push_u32(&mut out, 0);
+            push_u32(&mut out, 0);
+            push_u64(&mut out, self.entry_count);
+            push_u64(&mut out, self.entry_count);
+            push_u64(&mut out, cd_size);
+            push_u64(&mut out, cd_offset);
+
+            push_u32(&mut out, ZIP64_LOCATOR_SIGNATURE);
+            push_u32(&mut out, 0);
+            push_u64(&mut out, zip64_end_offset);
+            // total number of disks
+            push_u32(&mut out, 1);
+        }
+
+        push_u32(&mut out, END_SIGNATURE);
+        push_u16(&mut out, 0);
+        push_u16(&mut out, 0);
+        push_u16(&mut out, self.entry_count.min(MAX_U16) as u16);
+        push_u16(&mut out, self.entry_count.min(MAX_U16) as u16);
+        push_u32(&mut out, cd_size.min(MAX_U32) as u32);
+        push_u32(&mut out, cd_offset.min(MAX_U32) as u32);
+        // no comment
+        push_u16(&mut out, 0);
+        out
+    }
+}
+
+/// Length of the archive with all these files. The crc doesn't change the
+/// layout, so this goes through the same steps without the content.
+pub(crate) fn archive_len<'a>(entries: impl IntoIterator<Item = &'a ZipEntry>) -> u64 {
+    let mut writer = ZipWriter::new();
+    let mut len = 0;
+    for entry in entries {
+        len += writer.start_entry(entry).len() as u64;
+        len += entry.size;
+        len += writer.finish_entry(entry, 0).len() as u64;
+    }
+    len + writer.finish().len() as u64
+}
+
+/// extended timestamp, with the modification time in UTC, since the
+/// dos date time has no timezone, and a 2s resolution
+fn push_timestamp(extra: &mut Vec<u8>, modified_at: OffsetDateTime) {
+    push_u16(extra, TIMESTAMP_EXTRA_ID);
+    push_u16(extra, 5);
+    // only the modification time is present
+    extra.push(1);
+    let timestamp = modified_at.unix_timestamp().clamp(0, u32::MAX as i64);
+    push_u32(extra, timestamp as u32);
+}
+
+/// (time, date) in the ms-dos format, which can't represent anything before 1980
+fn dos_date_time(datetime: OffsetDateTime) -> (u16, u16) {
+    let datetime = datetime.to_offset(time::UtcOffset::UTC);
+    if datetime.year() < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let year = (datetime.year() - 1980).min(127) as u16;
+    let date = year << 9 | (u8::from(datetime.month()) as u16) << 5 | datetime.day() as u16;
+    let time = (datetime.hour() as u16) << 11
+        | (datetime.minute() as u16) << 5
+        | (datetime.second() as u16 / 2);
+    (time, date)
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn entry(name: &str, size: u64) -> ZipEntry {
+        ZipEntry {
+            name: name.to_string(),
+            size,
+            modified_at: datetime!(2023-11-05 14:30:12 UTC),
+        }
+    }
+
+    /// the archive with these files, written like the download of the files does
+    fn write_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new();
+        let mut archive = Vec::new();
+        for (name, content) in files {
+            let entry = entry(name, content.len() as u64);
+            archive.extend(writer.start_entry(&entry));
+            archive.extend_from_slice(content);
+            archive.extend(writer.finish_entry(&entry, crc32fast::hash(content)));
+        }
+        archive.extend(writer.finish());
+        archive
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn archive_len_is_what_is_written() {
+        let files: [(&str, &[u8]); 3] = [
+            ("empty", b""),
+            ("notes.txt", b"some notes"),
+            ("photos/été.jpg", &[0xff; 3000]),
+        ];
+        let archive = write_archive(&files);
+
+        let entries: Vec<_> = files
+            .iter()
+            .map(|(name, content)| entry(name, content.len() as u64))
+            .collect();
+        assert_eq!(archive_len(&entries), archive.len() as u64);
+        // not a zip64 archive, the end record is last
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(&archive, end), END_SIGNATURE);
+        assert_eq!(u16_at(&archive, end + 10), 3);
+    }
+
+    #[tokio::test]
+    async fn readable() {
+        let files: [(&str, &[u8]); 2] = [("notes.txt", b"some notes"), ("été.txt", b"summer")];
+        let archive = write_archive(&files);
+
+        let reader = async_zip::base::read::mem::ZipFileReader::new(archive)
+            .await
+            .unwrap();
+        assert_eq!(reader.file().entries().len(), files.len());
+        for (index, (name, content)) in files.iter().enumerate() {
+            let mut entry_reader = reader.reader_with_entry(index).await.unwrap();
+            let entry = entry_reader.entry();
+            assert_eq!(entry.filename().as_str().unwrap(), *name);
+            assert_eq!(entry.unix_permissions(), Some(0o100644));
+            let date = entry.last_modification_date();
+            assert_eq!((date.year(), date.month(), date.day()), (2023, 11, 5));
+            assert_eq!((date.hour(), date.minute(), date.second()), (14, 30, 12));
+
+            // checks the crc too
+            let mut read = Vec::new();
+            entry_reader.read_to_end_checked(&mut read).await.unwrap();
+            assert_eq!(read, *content);
+        }
+    }
+
+    #[test]
+    fn zip64_big_file() {
+        let big = entry("disk.img", 5 * GIB);
+        let small = entry("notes.txt", 10);
+        let mut writer = ZipWriter::new();
+
+        let header = writer.start_entry(&big);
+        assert_eq!(u16_at(&header, 4), VERSION_ZIP64);
+        assert_eq!(u32_at(&header, 18), MAX_U32 as u32);
+        assert_eq!(u32_at(&header, 22), MAX_U32 as u32);
+        // the zip64 extra field, after the name
+        let extra = 30 + big.name.len();
+        assert_eq!(u16_at(&header, extra), ZIP64_EXTRA_ID);
+        assert_eq!(u16_at(&header, extra + 2), 16);
+
+        let descriptor = writer.finish_entry(&big, 0x1234);
+        assert_eq!(descriptor.len(), 24);
+        assert_eq!(u32_at(&descriptor, 4), 0x1234);
+        assert_eq!(u64_at(&descriptor, 8), big.size);
+        assert_eq!(u64_at(&descriptor, 16), big.size);
+
+        // starts after 4 GiB, only its offset is in zip64
+        let small_offset = (header.len() + descriptor.len()) as u64 + big.size;
+        let header = writer.start_entry(&small);
+        assert_eq!(u16_at(&header, 4), VERSION);
+        let descriptor = writer.finish_entry(&small, 0);
+        assert_eq!(descriptor.len(), 16);
+        let cd_offset = small_offset + (header.len() + descriptor.len()) as u64 + small.size;
+
+        let end = writer.finish();
+        let central = |index| {
+            let mut at = 0;
+            for _ in 0..index {
+                at += 46
+                    + u16_at(&end, at + 28) as usize
+                    + u16_at(&end, at + 30) as usize
+                    + u16_at(&end, at + 32) as usize;
+            }
+            at
+        };
+
+        let big_cd = central(0);
+        assert_eq!(u32_at(&end, big_cd), CENTRAL_HEADER_SIGNATURE);
+        assert_eq!(u32_at(&end, big_cd + 20), MAX_U32 as u32);
+        assert_eq!(u32_at(&end, big_cd + 24), MAX_U32 as u32);
+        assert_eq!(u32_at(&end, big_cd + 42), 0);
+        let extra = big_cd + 46 + big.name.len();
+        assert_eq!(u16_at(&end, extra), ZIP64_EXTRA_ID);
+        assert_eq!(u16_at(&end, extra + 2), 16);
+        assert_eq!(u64_at(&end, extra + 4), big.size);
+        assert_eq!(u64_at(&end, extra + 12), big.size);
+
+        let small_cd = central(1);
+        assert_eq!(u32_at(&end, small_cd), CENTRAL_HEADER_SIGNATURE);
+        assert_eq!(u32_at(&end, small_cd + 20), 10);
+        assert_eq!(u32_at(&end, small_cd + 42), MAX_U32 as u32);
+        let extra = small_cd + 46 + small.name.len();
+        assert_eq!(u16_at(&end, extra), ZIP64_EXTRA_ID);
+        assert_eq!(u16_at(&end, extra + 2), 8);
+        assert_eq!(u64_at(&end, extra + 4), small_offset);
+
+        // the zip64 end record and its locator, then the regular end record
+        let cd_size = central(2) as u64;
+        let zip64_end = central(2);
+        assert_eq!(u32_at(&end, zip64_end), ZIP64_END_SIGNATURE);
+        assert_eq!(u64_at(&end, zip64_end + 32), 2);
+        assert_eq!(u64_at(&end, zip64_end + 40), cd_size);
+        assert_eq!(u64_at(&end, zip64_end + 48), cd_offset);
+        let locator = zip64_end + 56;
+        assert_eq!(u32_at(&end, locator), ZIP64_LOCATOR_SIGNATURE);
+        assert_eq!(u64_at(&end, locator + 8), cd_offset + cd_size);
+        let end_record = locator + 20;
+        assert_eq!(u32_at(&end, end_record), END_SIGNATURE);
+        assert_eq!(u32_at(&end, end_record + 16), MAX_U32 as u32);
+        assert_eq!(end.len(), end_record + 22);
+
+        assert_eq!(archive_len([&big, &small]), cd_offset + end.len() as u64);
+    }
+}
@@ -0,0 +1,87 @@
+"use strict";
+
+// lightbox for the gallery: the previews are shown one at a time, with
+// prev/next navigation. Without js, the links simply open the original images.
+
+const buildLightbox = () => {
+  const dialog = document.createElement("dialog");
+  dialog.className = "lightbox";
+  dialog.innerHTML = `
+    <img alt="">
+    <nav>
+      <button type="button" class="prev" title="Previous (←)">←</button>
+      <span class="caption"></span>
+      <a class="download" download>📥 Download</a>
+      <button type="button" class="close" title="Close (Esc)">✕</button>
+      <button type="button" class="next" title="Next (→)">→</button>
+    </nav>`;
+  document.body.appendChild(dialog);
+  return dialog;
+}
+
+const setupGallery = () => {
+  const links = Array.from(document.querySelectorAll(".gallery-item > a[data-preview]"));
+  if (links.length === 0) {
+    return;
+  }
+
+  const dialog = buildLightbox();
+  const img = dialog.querySelector("img");
+  const caption = dialog.querySelector(".caption");
+  const download = dialog.querySelector(".download");
+  let current = 0;
+
+  const show = idx => {
+    current = (idx + links.length) % links.length;
+    const link = links[current];
+    img.src = link.dataset.preview;
+    img.alt = link.dataset.name;
+    caption.textContent = `${link.dataset.name} (${current + 1}/${links.length})`;
+    download.href = link.href;
+    if (!dialog.open) {
+      dialog.showModal();
+    }
+  }
+
+  links.forEach((link, idx) => {
+    link.addEventListener("click", ev => {
+      ev.preventDefault();
+      show(idx);
+    });
+  });
+
+  dialog.querySelector(".prev").addEventListener("click", () => show(current - 1));
+  dialog.querySelector(".next").addEventListener("click", () => show(current + 1));
+  dialog.querySelector(".close").addEventListener("click", () => dialog.close());
+  // clicking on the backdrop closes the lightbox
+  dialog.addEventListener("click", ev => {
+    if (ev.target === dialog) {
+      dialog.close();
+    }
+  });
+  dialog.addEventListener("keydown", ev => {
+    if (ev.key === "ArrowLeft") {
+      show(current - 1);
+    } else if (ev.key === "ArrowRight") {
+      show(current + 1);
+    }
+  });
+
+  // swipe on phones
+  let touchStartX = null;
+  dialog.addEventListener("touchstart", ev => {
+    touchStartX = ev.changedTouches[0].clientX;
+  });
+  dialog.addEventListener("touchend", ev => {
+    if (touchStartX === null) {
+      return;
+    }
+    const dx = ev.changedTouches[0].clientX - touchStartX;
+    touchStartX = null;
+    if (Math.abs(dx) > 50) {
+      show(dx > 0 ? current - 1 : current + 1);
+    }
+  });
+}
+
+window.onload = setupGallery;
@@ -72,6 +72,10 @@ function addFile(id) {
   let inputElement = document.createElement("input");
   inputElement.type = "file";
   inputElement.name = `file_${counter}`;
+  let accept = document.querySelector("#upload-form").dataset.accept;
+  if (accept) {
+    inputElement.accept = accept;
+  }
   // inputElement.multiple = true;
 
   p.insertAdjacentElement("afterbegin", inputElement);